@@ -1,4 +1,6 @@
-use crate::util::LISTEN_DEFAULT_PORT;
+use crate::{
+    clipboard::TEXT_MIME, output::Output, raven::mailbox::Sort, util::LISTEN_DEFAULT_PORT,
+};
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -9,6 +11,9 @@ use clap::{Parser, Subcommand};
 /// Instantiate a receiving end and then send text messages or files to it from your other devices in your local network.
 /// Raven can be configured with `config.toml` in the raven home directory (either `$HOME/.raven` or `$RAVEN_HOME`).
 pub struct Cli {
+    /// How to print the results: for people, or as JSON or TOML records for scripts
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    pub output: Output,
     #[command(subcommand)]
     pub commands: Subcommands,
 }
@@ -17,34 +22,74 @@ pub struct Cli {
 pub enum Subcommands {
     /// Sends a message by a raven to another client
     Send {
-        /// The raven's destination address
+        /// The raven's destination: an ip address, a hostname, the name of a peer of the address book or of a device on the LAN,
+        /// optionally followed by `:PORT` (`[ADDRESS]:PORT` for ipv6)
         #[arg(long, value_name = "DESTINATION")]
         to: String,
-        /// The port where the raven must arrive
+        /// The port where the raven must arrive unless the destination has one, a peer or a device found by its name is reached on its own
         #[arg(short, long, value_name = "PORT", default_value_t = LISTEN_DEFAULT_PORT.into())]
         port: u16,
-        /// The message the raven must send
+        /// The message the raven must send, `-` or none to send what is piped to stdin
         #[arg(value_name = "MESSAGE")]
-        message: String,
+        message: Option<String>,
+        /// The name of the file sent when stdin isn't a short text
+        #[arg(long, value_name = "NAME")]
+        name: Option<String>,
+        /// Compresses the raven, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "no_compress")]
+        compress: bool,
+        /// Doesn't compress the raven, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "compress")]
+        no_compress: bool,
     },
 
-    /// Sends a file by a raven to another client
+    /// Sends files by a raven to another client
     SendFile {
-        /// The raven's destination address
+        /// The raven's destination: an ip address, a hostname, the name of a peer of the address book or of a device on the LAN,
+        /// optionally followed by `:PORT` (`[ADDRESS]:PORT` for ipv6)
         #[arg(long, value_name = "DESTINATION")]
         to: String,
-        /// The port where the raven must arrive
+        /// The port where the raven must arrive unless the destination has one, a peer or a device found by its name is reached on its own
         #[arg(short, long, value_name = "PORT", default_value_t = LISTEN_DEFAULT_PORT.into())]
         port: u16,
-        /// The file the raven must send
-        #[arg(value_name = "FILE")]
-        file: String,
+        /// The files the raven must send, or glob patterns matching them. Many files are sent together as a batch
+        #[arg(value_name = "FILE", required = true)]
+        files: Vec<String>,
+        /// Sends a directory with everything inside it
+        #[arg(short, long, default_value_t = false)]
+        recursive: bool,
+        /// Compresses the raven, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "no_compress")]
+        compress: bool,
+        /// Doesn't compress the raven, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "compress")]
+        no_compress: bool,
     },
     /// Manages the mailbox with your received messages and files
     Mailbox {
         #[command(subcommand)]
         commands: MailboxSubcommands,
     },
+    /// Manages the identity of this client and the peers it trusts
+    Trust {
+        #[command(subcommand)]
+        commands: TrustSubcommands,
+    },
+    /// Controls the `rvd` daemon running in the background
+    Daemon {
+        #[command(subcommand)]
+        commands: DaemonSubcommands,
+    },
+    /// Shares the clipboard with another client
+    Clip {
+        #[command(subcommand)]
+        commands: ClipSubcommands,
+    },
+    /// Manages the address book of the devices ravens are sent to by name, lists the devices on the LAN if none given
+    Peers {
+        #[command(subcommand)]
+        commands: Option<PeersSubcommands>,
+    },
 }
 
 #[derive(Subcommand)]
@@ -55,25 +100,227 @@ pub enum MailboxSubcommands {
         files: bool,
         #[arg(short, long, default_value_t = false)]
         messages: bool,
+        /// Only the ravens from senders whose name, fingerprint or address contains this
+        #[arg(long, value_name = "SENDER")]
+        from: Option<String>,
+        /// Only the ravens that arrived since this date (YYYY-MM-DD or YYYY-MM-DD HH:MM)
+        #[arg(long, value_name = "DATE")]
+        since: Option<String>,
+        /// Only the ravens that arrived until this date (YYYY-MM-DD or YYYY-MM-DD HH:MM)
+        #[arg(long, value_name = "DATE")]
+        until: Option<String>,
+        /// Only the ravens that weren't shown yet
+        #[arg(short, long, default_value_t = false)]
+        unread: bool,
+        /// Only the messages containing this text and the files whose name contains it
+        #[arg(long, value_name = "TEXT")]
+        matching: Option<String>,
+        /// Takes the text of `--matching` as a regular expression
+        #[arg(short, long, default_value_t = false)]
+        regex: bool,
+        /// The order in which the ravens are listed
+        #[arg(long, value_enum, default_value_t = Sort::Oldest)]
+        sort: Sort,
+        /// Lists at most this many ravens of each kind
+        #[arg(short, long, value_name = "N")]
+        limit: Option<usize>,
     },
-    /// Deletes a message or file from the mailbox
+    /// Prints the ravens as they arrive to the mailbox
+    Watch,
+    /// Searches the text of the messages in the mailbox
+    Search {
+        /// The text to look for, ignoring the case
+        #[arg(value_name = "QUERY")]
+        query: String,
+        /// Takes the query as a regular expression
+        #[arg(short, long, default_value_t = false)]
+        regex: bool,
+        /// The order in which the messages are listed
+        #[arg(long, value_enum, default_value_t = Sort::Oldest)]
+        sort: Sort,
+        /// Lists at most this many messages
+        #[arg(short, long, value_name = "N")]
+        limit: Option<usize>,
+    },
+    /// Deletes a message or file from the mailbox, a file in the trash is removed for good
     Delete {
-        /// The index of the message or file to delete
+        /// The id of the message or file to delete, or a unique prefix of it
         #[arg(value_name = "ID")]
-        index: usize,
+        id: String,
         #[arg(short, long, default_value_t = false)]
         file: bool,
         #[arg(short, long, default_value_t = false)]
         message: bool,
+        /// Keeps the received file where it is, only the entry is deleted
+        #[arg(short, long, default_value_t = false, conflicts_with_all = ["trash", "force"])]
+        keep: bool,
+        /// Moves the received file to the trash, `restore` puts it back
+        #[arg(short, long, default_value_t = false)]
+        trash: bool,
+        /// Removes the received file even if it was modified since it arrived
+        #[arg(long, default_value_t = false, conflicts_with = "trash")]
+        force: bool,
+    },
+    /// Puts a file deleted with `--trash` back in the mailbox
+    Restore {
+        /// The id of the file, or a unique prefix of it
+        #[arg(value_name = "ID")]
+        id: String,
+    },
+    /// Checks the received files are still what arrived, against the hashes stored when they arrived
+    Verify {
+        /// The id of the file to check, or a unique prefix of it, all the files if none
+        #[arg(value_name = "ID")]
+        id: Option<String>,
     },
     /// Opens a message or file from the mailbox
     Show {
-        /// The index of the message or file to open
+        /// The id of the message or file to open, or a unique prefix of it
         #[arg(value_name = "ID")]
-        index: usize,
+        id: String,
         #[arg(short, long, default_value_t = false)]
         file: bool,
         #[arg(short, long, default_value_t = false)]
         message: bool,
+        /// Writes only the text of the message or the content of the file to stdout, to pipe it elsewhere
+        #[arg(long, default_value_t = false)]
+        raw: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum TrustSubcommands {
+    /// Trusts a peer by its public key
+    Add {
+        /// The name to give to the peer
+        #[arg(value_name = "NAME")]
+        name: String,
+        /// The public key of the peer (shown by `rv trust fingerprint` on the peer)
+        #[arg(value_name = "KEY")]
+        key: String,
+        /// The ip address the peer must connect from to be trusted
+        #[arg(short, long, value_name = "ADDRESS")]
+        address: Option<String>,
+        /// The longest text message the peer may send in bytes, regardless of `receiver.limits.max_message`
+        #[arg(long, value_name = "BYTES")]
+        max_message: Option<u64>,
+        /// The biggest file the peer may send in bytes, regardless of `receiver.limits.max_file`
+        #[arg(long, value_name = "BYTES")]
+        max_file: Option<u64>,
+        /// How many bytes the peer may send every day, regardless of `receiver.limits.max_daily`
+        #[arg(long, value_name = "BYTES")]
+        max_daily: Option<u64>,
+        /// Puts the clipboards the peer sends on the clipboard right away
+        #[arg(long, default_value_t = false)]
+        clipboard: bool,
+    },
+    /// Lists the trusted peers
+    List,
+    /// Stops trusting a peer
+    Remove {
+        /// The name, public key or fingerprint of the peer
+        #[arg(value_name = "PEER")]
+        peer: String,
+    },
+    /// Shows the fingerprint and public key of this client
+    Fingerprint,
+}
+
+#[derive(Subcommand)]
+pub enum DaemonSubcommands {
+    /// Shows where the daemon listens, its uptime and the transfers in flight
+    Status,
+    /// Stops the daemon once the transfers in flight finish
+    Stop,
+    /// Makes the daemon load its configuration again
+    Reload,
+}
+
+#[derive(Subcommand)]
+pub enum ClipSubcommands {
+    /// Sends the content of the clipboard by a raven to another client
+    Send {
+        /// The raven's destination: an ip address, a hostname, the name of a peer of the address book or of a device on the LAN,
+        /// optionally followed by `:PORT` (`[ADDRESS]:PORT` for ipv6)
+        #[arg(long, value_name = "DESTINATION")]
+        to: String,
+        /// The port where the raven must arrive unless the destination has one, a peer or a device found by its name is reached on its own
+        #[arg(short, long, value_name = "PORT", default_value_t = LISTEN_DEFAULT_PORT.into())]
+        port: u16,
+        /// The MIME type of the content to take from the clipboard, it must be text
+        #[arg(long, value_name = "MIME", default_value_t = TEXT_MIME.into())]
+        mime: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PeersSubcommands {
+    /// Adds a device to the address book, to send ravens to it with `--to <name>`
+    Add {
+        /// The name to give to the device
+        #[arg(value_name = "NAME")]
+        name: String,
+        /// The ip address or the hostname of the device
+        #[arg(value_name = "ADDRESS")]
+        address: String,
+        /// The port where its ravens arrive
+        #[arg(short, long, value_name = "PORT", default_value_t = LISTEN_DEFAULT_PORT.into())]
+        port: u16,
+        /// The fingerprint of its key (shown by `rv trust fingerprint` on the device), nothing is sent to another key
+        #[arg(long, value_name = "FINGERPRINT")]
+        fingerprint: Option<String>,
+        /// Compresses the ravens sent to it, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "no_compress")]
+        compress: bool,
+        /// Doesn't compress the ravens sent to it, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "compress")]
+        no_compress: bool,
+        /// Encrypts the ravens sent to it, regardless of the `sender.encrypt` config
+        #[arg(long, overrides_with = "no_encrypt")]
+        encrypt: bool,
+        /// Doesn't encrypt the ravens sent to it, regardless of the `sender.encrypt` config
+        #[arg(long, overrides_with = "encrypt")]
+        no_encrypt: bool,
+    },
+    /// Lists the devices of the address book
+    List,
+    /// Changes a device of the address book, what isn't given is kept
+    Edit {
+        /// The name of the device
+        #[arg(value_name = "NAME")]
+        name: String,
+        /// The ip address or the hostname of the device
+        #[arg(short, long, value_name = "ADDRESS")]
+        address: Option<String>,
+        /// The port where its ravens arrive
+        #[arg(short, long, value_name = "PORT")]
+        port: Option<u16>,
+        /// The fingerprint of its key
+        #[arg(long, value_name = "FINGERPRINT")]
+        fingerprint: Option<String>,
+        /// Compresses the ravens sent to it, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "no_compress")]
+        compress: bool,
+        /// Doesn't compress the ravens sent to it, regardless of the `sender.compress` config
+        #[arg(long, overrides_with = "compress")]
+        no_compress: bool,
+        /// Encrypts the ravens sent to it, regardless of the `sender.encrypt` config
+        #[arg(long, overrides_with = "no_encrypt")]
+        encrypt: bool,
+        /// Doesn't encrypt the ravens sent to it, regardless of the `sender.encrypt` config
+        #[arg(long, overrides_with = "encrypt")]
+        no_encrypt: bool,
+    },
+    /// Removes a device from the address book
+    Remove {
+        /// The name of the device
+        #[arg(value_name = "NAME")]
+        name: String,
+    },
+    /// Lists the devices whose `rvd` answers on the LAN
+    Discover {
+        /// How long the devices have to answer in milliseconds, regardless of the `discovery.wait` config
+        #[arg(long, value_name = "MS")]
+        wait: Option<u64>,
     },
 }
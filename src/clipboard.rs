@@ -0,0 +1,276 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::Config, util};
+
+/// The MIME type of plain text, what the clipboard holds most of the time.
+pub const TEXT_MIME: &str = "text/plain";
+
+/// The placeholder replaced by the MIME type in the `clipboard.copy` and `clipboard.paste` commands.
+const MIME_PLACEHOLDER: &str = "{mime}";
+
+/// How long the tool writing the clipboard may run before it's killed.
+const COPY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether `mime` is a `type/subtype` MIME type, which is safe to pass as an argument to the clipboard tools.
+pub fn is_mime(mime: &str) -> bool {
+    // The restricted names of RFC 6838: they start with a letter or a digit, so never look like an option
+    let name = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+
+    match mime.split_once('/') {
+        Some((kind, subtype)) => name(kind) && name(subtype),
+        None => false,
+    }
+}
+
+/// The tools that reach the clipboard of the desktop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    Xclip,
+    Xsel,
+    /// `wl-copy` and `wl-paste`, for wayland.
+    WlClipboard,
+}
+
+/// Reads and writes the clipboard running the commands of a backend.
+#[derive(Debug, Clone)]
+pub enum Clipboard {
+    Backend(Backend),
+    /// The `clipboard.paste` and `clipboard.copy` commands, where `{mime}` is replaced by the MIME type.
+    Commands {
+        paste: Vec<String>,
+        copy: Vec<String>,
+    },
+}
+
+impl Backend {
+    /// Picks the backend of the running desktop among the installed ones.
+    fn detect() -> Option<Backend> {
+        if std::env::var_os("WAYLAND_DISPLAY").is_some() && util::on_path("wl-copy") {
+            Some(Backend::WlClipboard)
+        } else if util::on_path("xclip") {
+            Some(Backend::Xclip)
+        } else if util::on_path("xsel") {
+            Some(Backend::Xsel)
+        } else {
+            None
+        }
+    }
+
+    /// The commands printing the clipboard and reading stdin into it, in this order, for the given MIME type.
+    fn commands(&self, mime: &str) -> (Vec<String>, Vec<String>) {
+        // Plain text is left to the tools, which offer it in every form other programs may ask for
+        let typed = mime != TEXT_MIME;
+        let (paste, copy) = match self {
+            Backend::Xclip if typed => (
+                vec!["xclip", "-selection", "clipboard", "-o", "-t", mime],
+                vec!["xclip", "-selection", "clipboard", "-i", "-t", mime],
+            ),
+            Backend::Xclip => (
+                vec!["xclip", "-selection", "clipboard", "-o"],
+                vec!["xclip", "-selection", "clipboard", "-i"],
+            ),
+            // xsel only speaks plain text
+            Backend::Xsel => (
+                vec!["xsel", "--clipboard", "--output"],
+                vec!["xsel", "--clipboard", "--input"],
+            ),
+            Backend::WlClipboard if typed => (
+                vec!["wl-paste", "--no-newline", "--type", mime],
+                vec!["wl-copy", "--type", mime],
+            ),
+            Backend::WlClipboard => (vec!["wl-paste", "--no-newline"], vec!["wl-copy"]),
+        };
+
+        let owned = |args: Vec<&str>| args.into_iter().map(String::from).collect();
+        (owned(paste), owned(copy))
+    }
+}
+
+impl Clipboard {
+    /// The clipboard as configured: the `clipboard.paste` and `clipboard.copy` commands if both are set,
+    /// otherwise the commands of `clipboard.backend`, or of the backend detected if none is set.
+    pub fn new(config: &Config) -> Result<Self> {
+        if let (Some(paste), Some(copy)) = (&config.clipboard.paste, &config.clipboard.copy) {
+            if paste.is_empty() || copy.is_empty() {
+                bail!("clipboard.paste and clipboard.copy can't be empty");
+            }
+
+            return Ok(Self::Commands {
+                paste: paste.clone(),
+                copy: copy.clone(),
+            });
+        }
+
+        let backend = config.clipboard.backend.or_else(Backend::detect).context(
+            "No clipboard tool found, install xclip, xsel or wl-clipboard, or set clipboard.paste and clipboard.copy",
+        )?;
+
+        Ok(Self::Backend(backend))
+    }
+
+    /// The commands printing the clipboard and reading stdin into it, in this order, for the given MIME type.
+    fn commands(&self, mime: &str) -> Result<(Vec<String>, Vec<String>)> {
+        if !is_mime(mime) {
+            bail!("{:?} isn't a MIME type", mime);
+        }
+
+        Ok(match self {
+            Clipboard::Backend(backend) => backend.commands(mime),
+            Clipboard::Commands { paste, copy } => {
+                let typed = |args: &Vec<String>| {
+                    args.iter()
+                        .map(|arg| arg.replace(MIME_PLACEHOLDER, mime))
+                        .collect()
+                };
+                (typed(paste), typed(copy))
+            }
+        })
+    }
+
+    /// Reads the content of the clipboard of the given MIME type, which must be text.
+    pub fn paste(&self, mime: &str) -> Result<String> {
+        let (paste, _) = self.commands(mime)?;
+        let output = Command::new(&paste[0])
+            .args(&paste[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()
+            .context(format!("Running `{}` to read the clipboard", paste[0]))?;
+
+        if !output.status.success() {
+            bail!(
+                "`{}` failed to read the clipboard: {}",
+                paste[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        String::from_utf8(output.stdout)
+            .context(format!("The clipboard doesn't hold {} text", mime))
+    }
+
+    /// Puts `text` of the given MIME type on the clipboard.
+    /// The tool is killed if it doesn't finish within `COPY_TIMEOUT`.
+    pub fn copy(&self, text: &str, mime: &str) -> Result<()> {
+        let (_, copy) = self.commands(mime)?;
+        // Some tools keep running in the background to serve the clipboard, they must not hold our output
+        let mut child = Command::new(&copy[0])
+            .args(&copy[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .context(format!("Running `{}` to write the clipboard", copy[0]))?;
+
+        // Written from another thread, a tool that doesn't read its stdin would block us past the timeout
+        let mut stdin = child
+            .stdin
+            .take()
+            .context("Opening the stdin of the clipboard tool")?;
+        let bytes = text.as_bytes().to_vec();
+        let writer = thread::spawn(move || stdin.write_all(&bytes));
+
+        let deadline = Instant::now() + COPY_TIMEOUT;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                // Killing the tool closes the pipe, which unblocks the writer
+                let _ = child.kill();
+                let _ = child.wait();
+                let _ = writer.join();
+                bail!(
+                    "`{}` didn't write the clipboard within {} seconds",
+                    copy[0],
+                    COPY_TIMEOUT.as_secs()
+                );
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        writer
+            .join()
+            .map_err(|_| anyhow::anyhow!("The clipboard writer panicked"))?
+            .context(format!("Writing the clipboard through `{}`", copy[0]))?;
+
+        if !status.success() {
+            bail!("`{}` failed to write the clipboard: {}", copy[0], status);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_backend() {
+        let dir = std::env::temp_dir().join(format!("raven-test-clipboard-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let copied = dir.join("copied");
+
+        // A fake clipboard: pasting prints the MIME type, copying writes stdin to a file
+        let mut config = Config::new();
+        config.clipboard.paste = Some(vec!["echo".into(), "-n".into(), "<{mime}>".into()]);
+        config.clipboard.copy = Some(vec![
+            "sh".into(),
+            "-c".into(),
+            "cat > \"$0\"".into(),
+            copied.to_str().unwrap().into(),
+        ]);
+        let clipboard = Clipboard::new(&config).unwrap();
+
+        assert_eq!(clipboard.paste("text/html").unwrap(), "<text/html>");
+        clipboard.copy("copied text", TEXT_MIME).unwrap();
+        assert_eq!(std::fs::read_to_string(&copied).unwrap(), "copied text");
+
+        // Plain text is left to the tools, anything else is asked for by its type
+        let (paste, _) = Clipboard::Backend(Backend::Xclip)
+            .commands(TEXT_MIME)
+            .unwrap();
+        assert!(!paste.contains(&"-t".to_string()));
+        let (_, copy) = Clipboard::Backend(Backend::WlClipboard)
+            .commands("text/html")
+            .unwrap();
+        assert_eq!(copy, ["wl-copy", "--type", "text/html"]);
+
+        // Nothing but a `type/subtype` MIME type reaches the tools
+        assert!(clipboard.paste("--version").is_err());
+        assert!(clipboard.copy("text", "text/plain -o").is_err());
+        assert!(clipboard.paste("text").is_err());
+        assert!(clipboard.paste("-text/html").is_err());
+        assert!(clipboard.paste("text/-html").is_err());
+        assert!(is_mime("image/svg+xml"));
+
+        // A tool that never returns is killed
+        config.clipboard.copy = Some(vec!["sleep".into(), "60".into()]);
+        let started = Instant::now();
+        assert!(Clipboard::new(&config)
+            .unwrap()
+            .copy("text", TEXT_MIME)
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(30));
+
+        config.clipboard.paste = Some(vec!["false".into()]);
+        assert!(Clipboard::new(&config).unwrap().paste(TEXT_MIME).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,30 +1,147 @@
-use std::io::ErrorKind;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
-use crate::util::{self, ensure_folder, LISTEN_DEFAULT_ADDRESS, LISTEN_DEFAULT_PORT};
+use crate::{
+    clipboard::Backend,
+    discovery,
+    raven::protocol::Kind,
+    trust,
+    util::{
+        self, ensure_folder, DISCOVERY_DEFAULT_PORT, LISTEN_DEFAULT_ADDRESS, LISTEN_DEFAULT_PORT,
+    },
+};
 
 /// Describes the configuration of the raven client.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     /// The path to the home folder of the raven client.
     #[serde(skip, default = "Config::raven_home")]
     pub raven_home: String,
+    /// The name of this device, announced to the receivers of its ravens.
+    #[serde(default = "util::device_name")]
+    pub name: String,
     /// The receiver configuration.
     #[serde(default = "Receiver::default")]
     pub receiver: Receiver,
+    /// The sender configuration.
+    #[serde(default = "Sender::default")]
+    pub sender: Sender,
+    /// How the clipboard is reached.
+    #[serde(default)]
+    pub clipboard: ClipboardConfig,
+    /// How devices find each other on the LAN.
+    #[serde(default)]
+    pub discovery: Discovery,
+    /// The address book of the devices ravens are sent to by name, by their name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub peers: BTreeMap<String, Peer>,
 }
 
 /// Describes the configuration of the receiver.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Receiver {
-    /// The ipv4 address where the receiver will listen.
+    /// The ipv4 or ipv6 address where the receiver will listen, `::` listens on every address of both.
     #[serde(default = "util::listen_default_address")]
     pub address: String,
     /// The port where the receiver will listen.
     #[serde(default = "util::listen_default_port")]
     pub port: u16,
+    /// The types of ravens the receiver accepts.
+    #[serde(default = "Receiver::default_accept")]
+    pub accept: Vec<Kind>,
+    /// Whether the receiver only accepts ravens from trusted peers.
+    #[serde(default)]
+    pub trusted_only: bool,
+    /// Whether the receiver refuses unencrypted ravens.
+    #[serde(default)]
+    pub require_encryption: bool,
+    /// The limits to the ravens the receiver accepts, trusted peers may override them.
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+/// Describes limits to the ravens a receiver accepts, a limit that isn't set means no limit at all.
+/// Every limit is in bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Limits {
+    /// The maximum length of a text message.
+    pub max_message: Option<u64>,
+    /// The maximum size of a file.
+    pub max_file: Option<u64>,
+    /// The maximum amount of data a single sender may send in a day.
+    pub max_daily: Option<u64>,
+    /// The maximum size of all the files stored in the data folder.
+    pub max_storage: Option<u64>,
+}
+
+/// Describes the configuration of the sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sender {
+    /// Whether the ravens are encrypted.
+    #[serde(default = "util::default_true")]
+    pub encrypt: bool,
+    /// Whether the ravens are compressed, files that are already compressed never are.
+    #[serde(default = "util::default_true")]
+    pub compress: bool,
+}
+
+/// Describes how `rvd` announces this device on the LAN and how `rv` looks for the others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Discovery {
+    /// Whether `rvd` answers the devices looking for it.
+    #[serde(default = "util::default_true")]
+    pub enabled: bool,
+    /// The udp port where `rvd` answers, and where the other devices are looked for.
+    #[serde(default = "util::discovery_default_port")]
+    pub port: u16,
+    /// Where the devices are looked for: ipv4 addresses, broadcast ones included, with an optional port.
+    #[serde(default = "Discovery::default_targets")]
+    pub targets: Vec<String>,
+    /// How long the devices have to answer, in milliseconds.
+    #[serde(default = "Discovery::default_wait")]
+    pub wait: u64,
+}
+
+/// Describes a device of the address book, reached with `--to <name>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    /// The ip address or the hostname of the device.
+    pub address: String,
+    /// The port where its ravens arrive.
+    #[serde(default = "util::listen_default_port")]
+    pub port: u16,
+    /// The fingerprint of its key, nothing is sent to the device if it proves to own another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Whether the ravens sent to it are compressed, instead of `sender.compress`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compress: Option<bool>,
+    /// Whether the ravens sent to it are encrypted, instead of `sender.encrypt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypt: Option<bool>,
+}
+
+/// The names of the peers of the address book by their ip addresses, to name the senders of the ravens after them.
+#[derive(Debug, Clone, Default)]
+pub struct PeerNames(HashMap<IpAddr, String>);
+
+/// Describes how the clipboard is read and written, by default through the tool installed for the desktop.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClipboardConfig {
+    /// The tool used to reach the clipboard, detected among the installed ones if not set.
+    pub backend: Option<Backend>,
+    /// A command printing the clipboard, used instead of the backend together with `copy`.
+    /// `{mime}` in its arguments is replaced by the MIME type asked for.
+    pub paste: Option<Vec<String>>,
+    /// A command reading stdin into the clipboard, used instead of the backend together with `paste`.
+    /// `{mime}` in its arguments is replaced by the MIME type of the content.
+    pub copy: Option<Vec<String>>,
 }
 
 impl Config {
@@ -79,6 +196,78 @@ impl Config {
         }
     }
 
+    /// Loads the configuration from the `config.toml` in `raven_home`, without creating anything if it's missing.
+    pub fn read(raven_home: &str) -> Result<Self> {
+        let config_path = format!("{}/config.toml", raven_home);
+        let content = std::fs::read_to_string(&config_path).context(format!(
+            "Failed to read the config file from {}",
+            &config_path
+        ))?;
+        let mut config: Self = toml::from_str(&content).context(format!(
+            "Failed to deserialize the config file from {}",
+            &config_path
+        ))?;
+        config.raven_home = raven_home.to_string();
+
+        Ok(config)
+    }
+
+    /// Checks the values that deserialize fine but can't work.
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("The name of the device can't be empty");
+        }
+
+        if !util::is_ip_address(&self.receiver.address) {
+            bail!(
+                "receiver.address `{}` isn't an ip address",
+                self.receiver.address
+            );
+        }
+
+        if self.receiver.port == 0 {
+            bail!("receiver.port can't be 0");
+        }
+
+        if self.discovery.port == 0 {
+            bail!("discovery.port can't be 0");
+        }
+        discovery::targets(self)?;
+
+        for (name, peer) in self.peers.iter() {
+            peer.validate(name)?;
+        }
+
+        Ok(())
+    }
+
+    /// The peer of the address book called `name`, ignoring the case.
+    pub fn peer(&self, name: &str) -> Option<(&String, &Peer)> {
+        self.peers.get_key_value(name).or_else(|| {
+            self.peers
+                .iter()
+                .find(|(peer, _)| peer.eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// The names of the peers of the address book by their ip addresses, their hostnames looked up in the dns once.
+    /// A peer at an ip address wins over one whose hostname has the same one, one that can't be looked up is left out.
+    pub fn peer_names(&self) -> PeerNames {
+        let (literal, hostnames): (Vec<_>, Vec<_>) = self
+            .peers
+            .iter()
+            .partition(|(_, peer)| peer.address.parse::<IpAddr>().is_ok());
+
+        let mut names = HashMap::new();
+        for (name, peer) in literal.into_iter().chain(hostnames) {
+            for ip in peer.addresses() {
+                names.entry(ip).or_insert_with(|| name.clone());
+            }
+        }
+
+        PeerNames(names)
+    }
+
     /// Saves the configuration to the raven home folder in config.toml.
     pub fn save(&self) -> Result<()> {
         let config_path = format!("{}/config.toml", self.raven_home);
@@ -96,7 +285,12 @@ impl Default for Config {
         Config {
             // TODO: add context for when HOME env var is not set
             raven_home: Self::raven_home(),
+            name: util::device_name(),
             receiver: Default::default(),
+            sender: Default::default(),
+            clipboard: Default::default(),
+            discovery: Default::default(),
+            peers: BTreeMap::new(),
         }
     }
 }
@@ -106,6 +300,201 @@ impl Default for Receiver {
         Receiver {
             address: LISTEN_DEFAULT_ADDRESS.into(),
             port: LISTEN_DEFAULT_PORT,
+            accept: Self::default_accept(),
+            trusted_only: false,
+            require_encryption: false,
+            limits: Default::default(),
+        }
+    }
+}
+
+impl Peer {
+    /// Checks the values of the peer called `name` that deserialize fine but can't work.
+    pub fn validate(&self, name: &str) -> Result<()> {
+        if name.trim().is_empty() || util::is_ip_address(name) || name.contains(':') {
+            bail!(
+                "`{}` can't be the name of a peer, it must be a name and not an address",
+                name
+            );
+        }
+
+        if !util::is_ip_address(&self.address) && !util::is_hostname(&self.address) {
+            bail!(
+                "peers.{}.address `{}` isn't an ip address nor a hostname",
+                name,
+                self.address
+            );
+        }
+
+        if self.port == 0 {
+            bail!("peers.{}.port can't be 0", name);
+        }
+
+        if let Some(fingerprint) = &self.fingerprint {
+            if !trust::is_fingerprint(fingerprint) {
+                bail!(
+                    "peers.{}.fingerprint `{}` isn't a fingerprint like the ones `rv trust fingerprint` shows",
+                    name,
+                    fingerprint
+                );
+            }
         }
+
+        Ok(())
+    }
+
+    /// The ip addresses of the device, ipv4 ones mapped to ipv6 written as ipv4.
+    /// A hostname is looked up in the dns, it has none if the lookup fails.
+    pub fn addresses(&self) -> Vec<IpAddr> {
+        match self.address.parse::<IpAddr>() {
+            Ok(address) => vec![address.to_canonical()],
+            Err(_) => (self.address.as_str(), self.port)
+                .to_socket_addrs()
+                .map(|addresses| {
+                    addresses
+                        .map(|address| address.ip().to_canonical())
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the device is at `ip`, one of its `addresses`.
+    pub fn is_at(&self, ip: IpAddr) -> bool {
+        self.addresses().contains(&ip.to_canonical())
+    }
+}
+
+impl PeerNames {
+    /// The name of the peer at `address`, an ip address with or without a port.
+    /// The port is ignored, the one ravens arrive from isn't the one the peer receives them on.
+    pub fn at(&self, address: &str) -> Option<&str> {
+        let ip = match address.parse::<SocketAddr>() {
+            Ok(address) => address.ip(),
+            Err(_) => address.parse::<IpAddr>().ok()?,
+        };
+
+        self.0.get(&ip.to_canonical()).map(String::as_str)
+    }
+}
+
+impl Limits {
+    /// Overrides these limits with the ones set in `overrides`.
+    pub fn overridden_by(&self, overrides: &Limits) -> Limits {
+        Limits {
+            max_message: overrides.max_message.or(self.max_message),
+            max_file: overrides.max_file.or(self.max_file),
+            max_daily: overrides.max_daily.or(self.max_daily),
+            max_storage: overrides.max_storage.or(self.max_storage),
+        }
+    }
+}
+
+impl Default for Sender {
+    fn default() -> Self {
+        Sender {
+            encrypt: true,
+            compress: true,
+        }
+    }
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            enabled: true,
+            port: DISCOVERY_DEFAULT_PORT,
+            targets: Self::default_targets(),
+            wait: Self::default_wait(),
+        }
+    }
+}
+
+impl Discovery {
+    fn default_targets() -> Vec<String> {
+        vec!["255.255.255.255".into()]
+    }
+
+    fn default_wait() -> u64 {
+        1000
+    }
+}
+
+impl Receiver {
+    fn default_accept() -> Vec<Kind> {
+        vec![Kind::Text, Kind::File, Kind::Clipboard, Kind::Directory]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::new();
+        assert!(config.validate().is_ok());
+
+        config.receiver.address = "localhost:80".into();
+        assert!(config.validate().is_err());
+
+        config.receiver.address = "::1".into();
+        assert!(config.validate().is_ok());
+
+        config.receiver.address = "127.0.0.1".into();
+        config.receiver.port = 0;
+        assert!(config.validate().is_err());
+
+        config.receiver.port = 12345;
+        config.discovery.targets = vec!["192.168.1.255".into(), "10.0.0.2:12346".into()];
+        assert!(config.validate().is_ok());
+
+        config.discovery.targets.push("laptop".into());
+        assert!(config.validate().is_err());
+
+        config.discovery.targets.pop();
+        let mut peer = Peer {
+            address: "10.0.0.5".into(),
+            port: 12345,
+            fingerprint: Some("0a1b:2c3d:4e5f:6071:8293:a4b5:c6d7:e8f9".into()),
+            compress: None,
+            encrypt: Some(false),
+        };
+        config.peers.insert("laptop".into(), peer.clone());
+        assert!(config.validate().is_ok());
+        assert_eq!(config.peer("Laptop").unwrap().1, &peer);
+        assert_eq!(config.peer_names().at("10.0.0.5:53122"), Some("laptop"));
+        assert_eq!(config.peer_names().at("10.0.0.5"), Some("laptop"));
+        assert_eq!(config.peer_names().at("10.0.0.6:53122"), None);
+        assert_eq!(
+            config.peer_names().at("[::ffff:10.0.0.5]:53122"),
+            Some("laptop")
+        );
+
+        peer.address = "0:0::1".into();
+        config.peers.insert("laptop".into(), peer.clone());
+        assert_eq!(config.peer_names().at("[::1]:53122"), Some("laptop"));
+
+        peer.address = "localhost".into();
+        config.peers.insert("laptop".into(), peer.clone());
+        assert_eq!(config.peer_names().at("127.0.0.1:53122"), Some("laptop"));
+        assert_eq!(config.peer_names().at("10.0.0.5:53122"), None);
+
+        peer.fingerprint = Some("laptop".into());
+        config.peers.insert("laptop".into(), peer.clone());
+        assert!(config.validate().is_err());
+
+        peer.fingerprint = None;
+        peer.address = "laptop.home.arpa".into();
+        config.peers.insert("laptop".into(), peer.clone());
+        assert!(config.validate().is_ok());
+
+        peer.address = "laptop.home.arpa:4000".into();
+        config.peers.insert("laptop".into(), peer.clone());
+        assert!(config.validate().is_err());
+
+        peer.address = "10.0.0.5".into();
+        config.peers.insert("10.0.0.7".into(), peer);
+        assert!(config.validate().is_err());
     }
 }
@@ -0,0 +1,182 @@
+use std::os::unix::net::UnixStream;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cli::DaemonSubcommands,
+    config::Config,
+    output::{DaemonRecord, Output},
+    raven::protocol::Channel,
+    util,
+};
+
+/// The commands `rv` sends to a running `rvd` through its control socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlRequest {
+    /// Asks what the daemon is doing.
+    Status,
+    /// Asks the daemon to stop once the transfers in flight finish.
+    Stop,
+    /// Asks the daemon to load `config.toml` again.
+    Reload,
+}
+
+/// The answers of `rvd` to a `ControlRequest`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ControlResponse {
+    Status(Status),
+    /// The daemon stops accepting ravens and exits once the given amount of transfers finish.
+    Stopping {
+        transfers: usize,
+    },
+    Reloaded,
+    /// The daemon couldn't do what was asked.
+    Failed(String),
+}
+
+/// What a running daemon is doing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub pid: u32,
+    pub address: String,
+    pub port: u16,
+    /// For how many seconds the daemon has been running.
+    pub uptime: u64,
+    pub transfers: Vec<Transfer>,
+}
+
+/// A raven being received by the daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transfer {
+    /// The address the raven comes from.
+    pub peer: String,
+    /// For how many seconds the raven has been arriving.
+    pub elapsed: u64,
+}
+
+/// Where the daemon listens for control commands.
+pub fn socket_path(config: &Config) -> String {
+    format!("{}/rvd.sock", config.raven_home)
+}
+
+/// Where the daemon writes its process id while it runs.
+pub fn pid_path(config: &Config) -> String {
+    format!("{}/rvd.pid", config.raven_home)
+}
+
+/// Sends `request` to the daemon running in the raven home folder and waits for its answer.
+pub fn request(config: &Config, request: ControlRequest) -> Result<ControlResponse> {
+    let path = socket_path(config);
+    let stream = UnixStream::connect(&path)
+        .context(format!("Connecting to rvd at {}, is it running?", &path))?;
+
+    let mut channel = Channel::new(stream);
+    channel
+        .write_message(&request)
+        .context("Sending the command to rvd")?;
+    channel.read_message().context("Reading the answer of rvd")
+}
+
+/// Answers a single control command arriving on `stream` with `handle`.
+pub fn answer(
+    stream: UnixStream,
+    handle: impl FnOnce(ControlRequest) -> ControlResponse,
+) -> Result<()> {
+    let mut channel = Channel::new(stream);
+    let request = channel
+        .read_message()
+        .context("Reading the control command")?;
+    channel
+        .write_message(&handle(request))
+        .context("Answering the control command")
+}
+
+/// Formats an amount of seconds as hours, minutes and seconds.
+fn fmt_duration(seconds: u64) -> String {
+    format!(
+        "{}h {:02}m {:02}s",
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+pub fn manage(command: DaemonSubcommands, config: Config, output: Output) -> Result<()> {
+    let request = match command {
+        DaemonSubcommands::Status => ControlRequest::Status,
+        DaemonSubcommands::Stop => ControlRequest::Stop,
+        DaemonSubcommands::Reload => ControlRequest::Reload,
+    };
+
+    match self::request(&config, request)? {
+        ControlResponse::Status(status) => {
+            output.print(&DaemonRecord::Running(status.clone()), || {
+                println!("rvd is running (pid {})", status.pid);
+                println!(
+                    "Listening on {}",
+                    util::fmt_address(&status.address, status.port)
+                );
+                println!("Uptime: {}", fmt_duration(status.uptime));
+                println!("Active transfers: {}", status.transfers.len());
+                for transfer in status.transfers.iter() {
+                    println!("{} for {}", transfer.peer, fmt_duration(transfer.elapsed));
+                }
+            })
+        }
+        ControlResponse::Stopping { transfers } => {
+            output.print(&DaemonRecord::Stopping { transfers }, || {
+                if transfers == 0 {
+                    println!("rvd is stopping");
+                } else {
+                    println!(
+                        "rvd is stopping once {} transfers in flight finish",
+                        transfers
+                    );
+                }
+            })
+        }
+        ControlResponse::Reloaded => output.print(&DaemonRecord::Reloaded, || {
+            println!("rvd reloaded the configuration")
+        }),
+        ControlResponse::Failed(reason) => bail!("rvd failed: {}", reason),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn test_request() {
+        let mut config = Config::new();
+        config.raven_home = format!(
+            "{}/raven-test-control-{}",
+            std::env::temp_dir().to_str().unwrap(),
+            std::process::id()
+        );
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+        std::fs::create_dir_all(&config.raven_home).unwrap();
+
+        let listener = UnixListener::bind(socket_path(&config)).unwrap();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            answer(stream, |request| match request {
+                ControlRequest::Stop => ControlResponse::Stopping { transfers: 2 },
+                _ => ControlResponse::Failed("unexpected command".into()),
+            })
+            .unwrap();
+        });
+
+        let response = request(&config, ControlRequest::Stop).unwrap();
+        handle.join().unwrap();
+        assert!(matches!(
+            response,
+            ControlResponse::Stopping { transfers: 2 }
+        ));
+
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+    }
+}
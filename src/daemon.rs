@@ -1,25 +1,444 @@
-use std::{sync::Arc, thread};
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{TcpListener, UdpSocket},
+    os::unix::{
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use rv_raven::{config::Config, raven::receive::receive};
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use rv_raven::{
+    config::Config,
+    control::{self, ControlRequest, ControlResponse, Status, Transfer},
+    discovery::{self, Beacon},
+    raven::receive::{self, receive},
+    trust::Identity,
+};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+/// How many connections are served at the same time, the ones beyond are dropped.
+const MAX_CONNECTIONS: usize = 64;
+/// How long the listener waits between checks for new connections.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+/// How long a connection may stay silent before it's dropped, so a stalled peer doesn't hold it forever.
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long a control command may take to arrive, the commands are answered one at a time.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Parser)]
+#[command(version)]
+#[command(name = "rvd")]
+/// The raven daemon receives the ravens sent to this device.
+/// Use `rv daemon` to check on it, reload its configuration or stop it.
+struct Rvd {
+    /// Keeps running in the background, logging to `rvd.log` in the raven home directory
+    #[arg(short, long, default_value_t = false)]
+    detach: bool,
+}
+
+/// Everything the daemon shares with the threads receiving ravens and answering control commands.
+struct Daemon {
+    config: RwLock<Arc<Config>>,
+    /// Only missing if a reload failed to listen on both the new address and the old one.
+    listener: Mutex<Option<TcpListener>>,
+    identity: Arc<Identity>,
+    started: Instant,
+    /// Set once the daemon was asked to stop, no more connections are accepted.
+    stopping: AtomicBool,
+    /// The peers of the transfers in flight and when they started, by an id given when they started.
+    transfers: Mutex<HashMap<u64, (String, Instant)>>,
+    next_transfer: AtomicU64,
+}
+
+/// Keeps a transfer listed in the daemon status for as long as it lives.
+struct InFlight<'a> {
+    daemon: &'a Daemon,
+    id: u64,
+}
 
 fn main() -> Result<()> {
-    let config = Arc::new(Config::load()?);
-    
-    let listener = std::net::TcpListener::bind(format!("{}:{}", config.receiver.address, config.receiver.port))?;
-    println!("Listening on {}:{}", config.receiver.address, config.receiver.port);
+    let rvd = Rvd::parse();
+    let config = Config::load()?;
+    config.validate()?;
+
+    if control::request(&config, ControlRequest::Status).is_ok() {
+        bail!("rvd is already running in {}", config.raven_home);
+    }
+
+    if rvd.detach {
+        return detach(&config);
+    }
+
+    let identity = Arc::new(Identity::load(&config)?);
+
+    let listener = listen(&config)?;
+
+    // Whatever is left is from a daemon that didn't stop gracefully
+    let socket = control::socket_path(&config);
+    let _ = std::fs::remove_file(&socket);
+    let commands = UnixListener::bind(&socket)
+        .context(format!("Opening the control socket at {}", &socket))?;
+
+    let pid = control::pid_path(&config);
+    std::fs::write(&pid, std::process::id().to_string())
+        .context(format!("Writing the pid file {}", &pid))?;
+
+    let daemon = Arc::new(Daemon::new(config, listener, identity));
+
+    let controlled = Arc::clone(&daemon);
+    thread::spawn(move || {
+        for stream in commands.incoming() {
+            match stream {
+                Ok(stream) => controlled.answer(stream),
+                Err(e) => eprintln!("Control error: {}", e),
+            }
+        }
+    });
+
+    let announced = Arc::clone(&daemon);
+    thread::spawn(move || announced.announce());
+
+    let signalled = Arc::clone(&daemon);
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            println!("Received signal {}", signal);
+
+            if signal == SIGHUP {
+                if let Err(e) = signalled.reload() {
+                    eprintln!("Error: {:#}", e);
+                }
+            } else {
+                signalled.stop();
+            }
+        }
+    });
 
     // Accept connections and process them, spawning a new thread for each one
-    for stream in listener.incoming() {
-        let stream = stream?;
-        let config = Arc::clone(&config);
+    let mut handles: Vec<JoinHandle<()>> = Vec::new();
+    while !daemon.stopping.load(Ordering::SeqCst) {
+        let accepted = match daemon.listener.lock().unwrap().as_ref() {
+            Some(listener) => listener.accept(),
+            None => Err(ErrorKind::WouldBlock.into()),
+        };
+        let (stream, address) = match accepted {
+            Ok(connection) => connection,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(ACCEPT_POLL);
+                continue;
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+
+        handles.retain(|handle| !handle.is_finished());
+        if handles.len() >= MAX_CONNECTIONS {
+            eprintln!("Dropping connection from {}: too many connections", address);
+            continue;
+        }
+
+        if let Err(e) = stream
+            .set_nonblocking(false)
+            .and_then(|()| stream.set_read_timeout(Some(READ_TIMEOUT)))
+        {
+            eprintln!("Dropping connection from {}: {}", address, e);
+            continue;
+        }
+        let daemon = Arc::clone(&daemon);
+        handles.push(thread::spawn(move || {
+            let _in_flight = daemon.in_flight(address.to_string());
+            let config = Arc::clone(&daemon.config.read().unwrap());
 
-        thread::spawn(move || {
-            if let Err(e) = receive(stream, config) {
+            if let Err(e) = receive(stream, config, Arc::clone(&daemon.identity)) {
                 eprintln!("Error: {}", e);
             }
-        });
+        }));
     }
 
+    handles.retain(|handle| !handle.is_finished());
+    if !handles.is_empty() {
+        println!("Waiting for {} transfers in flight", handles.len());
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+
+    let _ = std::fs::remove_file(&socket);
+    let _ = std::fs::remove_file(&pid);
+    println!("Stopped");
+
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Opens the tcp listener where the ravens arrive.
+fn listen(config: &Config) -> Result<TcpListener> {
+    let listener = receive::listen(&config.receiver.address, config.receiver.port)?;
+    listener.set_nonblocking(true)?;
+    println!("Listening on {}", listener.local_addr()?);
+
+    Ok(listener)
+}
+
+/// Starts the daemon again in the background, detached from the terminal and logging to `rvd.log`.
+fn detach(config: &Config) -> Result<()> {
+    let log_path = format!("{}/rvd.log", config.raven_home);
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .context(format!("Opening the log file {}", &log_path))?;
+
+    let child = Command::new(std::env::current_exe()?)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        // Out of the terminal process group, so a ctrl-c in the terminal doesn't reach it
+        .process_group(0)
+        .spawn()
+        .context("Starting rvd in the background")?;
+
+    println!(
+        "rvd is running in the background (pid {}), logging to {}",
+        child.id(),
+        &log_path
+    );
+
+    Ok(())
+}
+
+impl Daemon {
+    fn new(config: Config, listener: TcpListener, identity: Arc<Identity>) -> Self {
+        Daemon {
+            config: RwLock::new(Arc::new(config)),
+            listener: Mutex::new(Some(listener)),
+            identity,
+            started: Instant::now(),
+            stopping: AtomicBool::new(false),
+            transfers: Mutex::new(HashMap::new()),
+            next_transfer: AtomicU64::new(0),
+        }
+    }
+
+    fn answer(&self, stream: UnixStream) {
+        // A client that connects and says nothing would keep every other command waiting
+        let answered = stream
+            .set_read_timeout(Some(CONTROL_TIMEOUT))
+            .map_err(anyhow::Error::from)
+            .and_then(|()| control::answer(stream, |request| self.control(request)));
+        if let Err(e) = answered {
+            eprintln!("Control error: {}", e);
+        }
+    }
+
+    fn control(&self, request: ControlRequest) -> ControlResponse {
+        match request {
+            ControlRequest::Status => {
+                let config = self.config.read().unwrap();
+
+                ControlResponse::Status(Status {
+                    pid: std::process::id(),
+                    address: config.receiver.address.clone(),
+                    port: config.receiver.port,
+                    uptime: self.started.elapsed().as_secs(),
+                    transfers: self.transfers(),
+                })
+            }
+            ControlRequest::Stop => {
+                self.stop();
+
+                ControlResponse::Stopping {
+                    transfers: self.transfers.lock().unwrap().len(),
+                }
+            }
+            ControlRequest::Reload => match self.reload() {
+                Ok(()) => ControlResponse::Reloaded,
+                Err(e) => {
+                    eprintln!("Error: {:#}", e);
+                    ControlResponse::Failed(format!("{:#}", e))
+                }
+            },
+        }
+    }
+
+    /// Loads `config.toml` again, the listener is only opened again if the address or the port changed.
+    /// If the new config is missing, invalid or can't be listened on, the daemon keeps running with the old one.
+    ///
+    /// Transfers in flight finish with the config they started with.
+    fn reload(&self) -> Result<()> {
+        let old = Arc::clone(&self.config.read().unwrap());
+        let config = Config::read(&old.raven_home).context("Keeping the old configuration")?;
+        config.validate().context("Keeping the old configuration")?;
+
+        // Holding the listener lock, no connection is accepted until both are swapped
+        let mut listener = self.listener.lock().unwrap();
+        if old.receiver.address != config.receiver.address
+            || old.receiver.port != config.receiver.port
+        {
+            // Listening on every ipv6 address holds the port on the ipv4 ones too, the old listener is closed first
+            *listener = None;
+            match listen(&config) {
+                Ok(opened) => *listener = Some(opened),
+                Err(e) => {
+                    *listener = Some(listen(&old).context(format!(
+                        "Listening on the old address again after failing to listen on the new one ({:#})",
+                        e
+                    ))?);
+                    return Err(e).context("Keeping the old configuration");
+                }
+            }
+        }
+
+        *self.config.write().unwrap() = Arc::new(config);
+        println!("Reloaded the configuration");
+
+        Ok(())
+    }
+
+    /// Answers the devices looking for this one on the LAN, until the daemon stops.
+    ///
+    /// The socket is opened again whenever a reload changes the address or the discovery port,
+    /// and closed while discovery is disabled.
+    fn announce(&self) {
+        let mut bound: Option<(String, u16)> = None;
+        let mut socket: Option<UdpSocket> = None;
+
+        while !self.stopping.load(Ordering::SeqCst) {
+            let config = Arc::clone(&self.config.read().unwrap());
+            let wanted = config
+                .discovery
+                .enabled
+                .then(|| (config.receiver.address.clone(), config.discovery.port));
+            if wanted != bound {
+                socket = None;
+                if let Some((address, port)) = &wanted {
+                    match discovery::listen(address, *port) {
+                        Ok(opened) => socket = Some(opened),
+                        Err(e) => eprintln!("Error: {:#}", e),
+                    }
+                }
+                bound = wanted;
+            }
+
+            let Some(socket) = &socket else {
+                thread::sleep(discovery::ANSWER_POLL);
+                continue;
+            };
+            match discovery::answer(socket, &Beacon::new(&config, &self.identity)) {
+                Ok(Some(from)) => println!("Answered the probe of {}", from),
+                Ok(None) => {}
+                Err(e) => eprintln!("Error: {:#}", e),
+            }
+        }
+    }
+
+    /// Stops accepting connections, the daemon exits once the transfers in flight finish.
+    fn stop(&self) {
+        if !self.stopping.swap(true, Ordering::SeqCst) {
+            println!("Stopping");
+        }
+    }
+
+    /// Lists the transfers in flight.
+    fn transfers(&self) -> Vec<Transfer> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(peer, started)| Transfer {
+                peer: peer.clone(),
+                elapsed: started.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    fn in_flight(&self, peer: String) -> InFlight<'_> {
+        let id = self.next_transfer.fetch_add(1, Ordering::SeqCst);
+        self.transfers
+            .lock()
+            .unwrap()
+            .insert(id, (peer, Instant::now()));
+
+        InFlight { daemon: self, id }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.daemon.transfers.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let mut config = Config::new();
+        config.raven_home = format!(
+            "{}/raven-test-daemon-reload-{}",
+            std::env::temp_dir().to_str().unwrap(),
+            std::process::id()
+        );
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+        config.receiver.address = "::".into();
+        config.receiver.port = 0;
+        let listener = listen(&config).unwrap();
+        config.receiver.port = listener.local_addr().unwrap().port();
+        let identity = Arc::new(Identity::load(&config).unwrap());
+        let daemon = Daemon::new(config.clone(), listener, identity);
+        let address = || {
+            daemon
+                .listener
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .local_addr()
+                .unwrap()
+        };
+
+        // Only the address changes, the port is still held by the old listener until it's closed
+        config.receiver.address = "127.0.0.1".into();
+        config.save().unwrap();
+        daemon.reload().unwrap();
+        assert_eq!(
+            address().to_string(),
+            format!("127.0.0.1:{}", config.receiver.port)
+        );
+
+        // An address that can't be listened on keeps the old one
+        config.receiver.address = "192.0.2.1".into();
+        config.save().unwrap();
+        assert!(daemon.reload().is_err());
+        assert_eq!(
+            address().to_string(),
+            format!("127.0.0.1:{}", config.receiver.port)
+        );
+        assert_eq!(daemon.config.read().unwrap().receiver.address, "127.0.0.1");
+
+        // A missing config isn't created again
+        let path = format!("{}/config.toml", config.raven_home);
+        std::fs::remove_file(&path).unwrap();
+        let err = daemon.reload().unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to read the config file"));
+        assert!(!std::path::Path::new(&path).exists());
+
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+    }
+}
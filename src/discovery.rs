@@ -0,0 +1,448 @@
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    output::{DeviceRecord, DevicesRecord, Output},
+    raven::protocol::{MAGIC, PROTOCOL_VERSION},
+    trust::Identity,
+};
+
+/// How long `rvd` waits for a probe before checking whether its configuration changed.
+pub const ANSWER_POLL: Duration = Duration::from_secs(1);
+/// The largest datagram a beacon or a probe may take.
+const MAX_DATAGRAM_LEN: usize = 1024;
+
+/// The datagrams devices look for each other with, `MAGIC` followed by the bincode of the signal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Signal {
+    /// Asks every `rvd` reached to answer with its beacon.
+    Probe,
+    Beacon(Beacon),
+}
+
+/// How a device running `rvd` announces itself on the LAN.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Beacon {
+    pub name: String,
+    /// The tcp port where its ravens arrive.
+    pub port: u16,
+    /// The fingerprint of its public key, to check against the one `rv trust fingerprint` shows on it.
+    pub fingerprint: String,
+    /// The protocol version it speaks.
+    pub version: u16,
+}
+
+/// A device that answered a probe.
+#[derive(Debug, Clone)]
+pub struct Device {
+    /// The ipv4 address its beacon came from.
+    pub address: String,
+    pub beacon: Beacon,
+}
+
+impl Beacon {
+    /// The beacon of this device, as configured in `config`.
+    pub fn new(config: &Config, identity: &Identity) -> Self {
+        Beacon {
+            name: config.name.clone(),
+            port: config.receiver.port,
+            fingerprint: identity.fingerprint(),
+            version: PROTOCOL_VERSION,
+        }
+    }
+}
+
+impl Device {
+    pub fn record(&self) -> DeviceRecord {
+        DeviceRecord {
+            name: self.beacon.name.clone(),
+            address: self.address.clone(),
+            port: self.beacon.port,
+            fingerprint: self.beacon.fingerprint.clone(),
+            version: self.beacon.version,
+        }
+    }
+}
+
+fn encode(signal: &Signal) -> Result<Vec<u8>> {
+    let mut datagram = MAGIC.to_vec();
+    datagram.extend(bincode::serialize(signal)?);
+
+    Ok(datagram)
+}
+
+/// Decodes a datagram, anything that isn't a signal of a raven device is `None`.
+fn decode(datagram: &[u8]) -> Option<Signal> {
+    let signal = datagram.strip_prefix(&MAGIC)?;
+
+    bincode::deserialize(signal).ok()
+}
+
+/// Opens the udp socket where `rvd` answers the probes, on `address` and the `discovery.port`.
+/// The probes travel over ipv4, listening on every ipv6 address means listening on every ipv4 one.
+pub fn listen(address: &str, port: u16) -> Result<UdpSocket> {
+    let address = match address.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        Ok(ip) => ip,
+        Err(_) => bail!("Invalid ip address {}", address),
+    };
+    let socket = UdpSocket::bind((address, port)).context(format!(
+        "Answering discovery on {}",
+        SocketAddr::new(address, port)
+    ))?;
+    socket.set_read_timeout(Some(ANSWER_POLL))?;
+    println!("Answering discovery on {}", SocketAddr::new(address, port));
+
+    Ok(socket)
+}
+
+/// Waits for a single datagram on `socket` and answers it with `beacon` if it's a probe.
+///
+/// Returns where the probe came from, `None` if the wait timed out or the datagram wasn't a probe.
+pub fn answer(socket: &UdpSocket, beacon: &Beacon) -> Result<Option<SocketAddr>> {
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    let (len, from) = match socket.recv_from(&mut buf) {
+        Ok(received) => received,
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e).context("Waiting for probes"),
+    };
+
+    match decode(&buf[..len]) {
+        Some(Signal::Probe) => {
+            socket
+                .send_to(&encode(&Signal::Beacon(beacon.clone()))?, from)
+                .context(format!("Answering the probe of {}", from))?;
+
+            Ok(Some(from))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Where the probes are sent: the `discovery.targets`, each an ipv4 address with an optional port.
+/// The ones without a port are sent to the `discovery.port`.
+pub fn targets(config: &Config) -> Result<Vec<SocketAddrV4>> {
+    config
+        .discovery
+        .targets
+        .iter()
+        .map(|target| match target.parse::<SocketAddrV4>() {
+            Ok(target) => Ok(target),
+            Err(_) => match target.parse::<Ipv4Addr>() {
+                Ok(address) => Ok(SocketAddrV4::new(address, config.discovery.port)),
+                Err(_) => bail!(
+                    "discovery.targets `{}` isn't an ipv4 address, with or without a port",
+                    target
+                ),
+            },
+        })
+        .collect()
+}
+
+/// Probes the `discovery.targets` and collects the beacons arriving within `wait`, sorted by name.
+///
+/// A device answering more than once, reached by more than a target, is listed once.
+pub fn discover(config: &Config, wait: Duration) -> Result<Vec<Device>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).context("Opening a udp socket")?;
+    socket.set_broadcast(true)?;
+
+    let probe = encode(&Signal::Probe)?;
+    let mut probed = 0;
+    let mut error = None;
+    for target in targets(config)? {
+        match socket.send_to(&probe, target) {
+            Ok(_) => probed += 1,
+            Err(e) => error = Some(anyhow::Error::new(e).context(format!("Probing {}", target))),
+        }
+    }
+    if probed == 0 {
+        return Err(error.unwrap_or(anyhow::anyhow!("discovery.targets is empty")))
+            .context("Looking for devices on the LAN");
+    }
+
+    let mut devices: Vec<Device> = Vec::new();
+    let deadline = Instant::now() + wait;
+    let mut buf = [0; MAX_DATAGRAM_LEN];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(left))?;
+
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e).context("Waiting for the devices on the LAN to answer"),
+        };
+        let Some(Signal::Beacon(beacon)) = decode(&buf[..len]) else {
+            continue;
+        };
+
+        let address = from.ip().to_string();
+        if !devices
+            .iter()
+            .any(|device| device.address == address && device.beacon.port == beacon.port)
+        {
+            devices.push(Device { address, beacon });
+        }
+    }
+
+    devices.sort_by(|a, b| a.beacon.name.cmp(&b.beacon.name));
+    Ok(devices)
+}
+
+/// Finds the device called `name`, ignoring the case, among the ones answering on the LAN.
+/// Its beacon tells the port it announced and the fingerprint its key must have once connected.
+pub fn find(name: &str, config: &Config) -> Result<Device> {
+    let devices = discover(config, Duration::from_millis(config.discovery.wait))?;
+    let named: Vec<&Device> = devices
+        .iter()
+        .filter(|device| device.beacon.name.eq_ignore_ascii_case(name))
+        .collect();
+
+    match named[..] {
+        [device] => {
+            eprintln!(
+                "Found {} at {}:{}",
+                device.beacon.name, device.address, device.beacon.port
+            );
+            Ok((*device).clone())
+        }
+        [] => bail!(
+            "No device called `{}` answered on the LAN, is its rvd running?",
+            name
+        ),
+        _ => bail!(
+            "{} devices are called `{}` ({}), send to one of their addresses",
+            named.len(),
+            name,
+            named
+                .iter()
+                .map(|device| format!("{}:{}", device.address, device.beacon.port))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Lists the devices answering on the LAN, waiting `wait` milliseconds for them or the `discovery.wait`.
+pub fn discovered(wait: Option<u64>, config: Config, output: Output) -> Result<()> {
+    let wait = Duration::from_millis(wait.unwrap_or(config.discovery.wait));
+    let record = DevicesRecord {
+        devices: discover(&config, wait)?
+            .iter()
+            .map(Device::record)
+            .collect(),
+    };
+
+    output.print(&record, || {
+        if record.devices.is_empty() {
+            println!("No device answered on the LAN");
+        }
+        for device in record.devices.iter() {
+            println!(
+                "{} at {}:{} [{}]{}",
+                device.name,
+                device.address,
+                device.port,
+                device.fingerprint,
+                if device.version != PROTOCOL_VERSION {
+                    format!(", speaks protocol version {}", device.version)
+                } else {
+                    String::new()
+                }
+            );
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        peers,
+        raven::{receive, send},
+    };
+
+    /// Answers the probes on `socket` with `beacon` until `stop` is set, like `rvd` does.
+    fn daemon(socket: UdpSocket, beacon: Beacon, stop: Arc<AtomicBool>) -> thread::JoinHandle<()> {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                answer(&socket, &beacon).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_discover() {
+        let beacons = [("laptop", 23461), ("desktop", 23462)].map(|(name, port)| Beacon {
+            name: name.into(),
+            port,
+            fingerprint: format!("{}-fingerprint", name),
+            version: PROTOCOL_VERSION,
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut config = Config::new();
+        config.discovery.targets = Vec::new();
+        let mut handles = Vec::new();
+        for beacon in beacons.iter() {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            config
+                .discovery
+                .targets
+                .push(socket.local_addr().unwrap().to_string());
+            handles.push(daemon(socket, beacon.clone(), Arc::clone(&stop)));
+        }
+        // Reached twice, listed once
+        let again = config.discovery.targets[0].clone();
+        config.discovery.targets.push(again);
+        config.discovery.wait = 500;
+
+        let devices = discover(&config, Duration::from_millis(500)).unwrap();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].beacon, beacons[1]);
+        assert_eq!(devices[1].beacon, beacons[0]);
+        assert!(devices.iter().all(|device| device.address == "127.0.0.1"));
+
+        let laptop = find("LAPTOP", &config).unwrap();
+        assert_eq!(laptop.address, "127.0.0.1");
+        assert_eq!(laptop.beacon, beacons[0]);
+        assert!(find("phone", &config).is_err());
+
+        stop.store(true, Ordering::SeqCst);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    /// Runs a receiver called `name` on loopback for a single raven, announcing the key `fingerprint` on
+    /// `socket` or its own one. Returns its configuration and the thread receiving.
+    fn device(
+        name: &str,
+        socket: UdpSocket,
+        fingerprint: Option<String>,
+        stop: Arc<AtomicBool>,
+    ) -> (
+        Config,
+        thread::JoinHandle<()>,
+        thread::JoinHandle<Result<()>>,
+    ) {
+        let mut config = Config::new();
+        config.name = name.into();
+        config.raven_home = format!(
+            "{}/raven-test-device-{}-{}",
+            std::env::temp_dir().to_str().unwrap(),
+            name,
+            std::process::id()
+        );
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+
+        let listener = receive::listen("127.0.0.1", 0).unwrap();
+        config.receiver.port = listener.local_addr().unwrap().port();
+        let identity = Identity::load(&config).unwrap();
+        let mut beacon = Beacon::new(&config, &identity);
+        beacon.fingerprint = fingerprint.unwrap_or(beacon.fingerprint);
+
+        let answering = daemon(socket, beacon, stop);
+        let receiving = config.clone();
+        let receiver = thread::spawn(move || {
+            let (stream, _) = listener.accept()?;
+            receive::receive(stream, Arc::new(receiving), Arc::new(identity))
+        });
+
+        (config, answering, receiver)
+    }
+
+    #[test]
+    fn test_send_to_device() {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut sending = Config::new();
+        sending.raven_home = format!(
+            "{}/raven-test-device-sender-{}",
+            std::env::temp_dir().to_str().unwrap(),
+            std::process::id()
+        );
+        let _ = std::fs::remove_dir_all(&sending.raven_home);
+        let identity = Identity::load(&sending).unwrap();
+        sending.discovery.targets = Vec::new();
+        sending.discovery.wait = 500;
+
+        // The desktop answers with the key of the sender, any other one than its own
+        let mut devices = Vec::new();
+        for (name, fingerprint) in [("laptop", None), ("desktop", Some(identity.fingerprint()))] {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            sending
+                .discovery
+                .targets
+                .push(socket.local_addr().unwrap().to_string());
+            devices.push(device(name, socket, fingerprint, Arc::clone(&stop)));
+        }
+
+        let to = peers::resolve("laptop", 12345, &sending).unwrap();
+        assert_eq!(to.port, devices[0].0.receiver.port);
+        assert_eq!(to.device.as_ref().unwrap().0, "laptop");
+        send::send(&to, "found you".into(), &sending, &identity).unwrap();
+
+        let to = peers::resolve("desktop", 12345, &sending).unwrap();
+        let err = send::send(&to, "who are you".into(), &sending, &identity).unwrap_err();
+        assert!(err.to_string().contains("isn't the device desktop"));
+
+        stop.store(true, Ordering::SeqCst);
+        let (laptop, desktop) = (devices.remove(0), devices.remove(0));
+        laptop.1.join().unwrap();
+        laptop.2.join().unwrap().unwrap();
+        desktop.1.join().unwrap();
+        assert!(desktop.2.join().unwrap().is_err());
+
+        let mailbox =
+            std::fs::read_to_string(format!("{}/mailbox.toml", laptop.0.raven_home)).unwrap();
+        assert!(mailbox.contains("found you"));
+        assert!(!std::path::Path::new(&format!("{}/mailbox.toml", desktop.0.raven_home)).exists());
+    }
+
+    #[test]
+    fn test_targets() {
+        let mut config = Config::new();
+        config.discovery.port = 23460;
+        config.discovery.targets = vec!["192.168.1.255".into(), "10.0.0.2:4000".into()];
+        assert_eq!(
+            targets(&config).unwrap(),
+            vec![
+                "192.168.1.255:23460".parse::<SocketAddrV4>().unwrap(),
+                "10.0.0.2:4000".parse().unwrap()
+            ]
+        );
+
+        config.discovery.targets.push("laptop.local".into());
+        assert!(targets(&config).is_err());
+
+        assert!(decode(b"RAVX").is_none());
+        assert!(matches!(
+            decode(&encode(&Signal::Probe).unwrap()),
+            Some(Signal::Probe)
+        ));
+    }
+}
@@ -1 +1,82 @@
+use std::fmt::Display;
 
+/// Errors that can happen while speaking the raven wire protocol.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The peer didn't start the conversation with the raven magic bytes.
+    BadMagic([u8; 4]),
+    /// The peer speaks a protocol version that this client doesn't know.
+    UnsupportedVersion { theirs: u16, ours: u16 },
+    /// The header carries a message type that this client doesn't know.
+    UnknownKind(u8),
+    /// The raven in the body doesn't match the message type announced in the header.
+    KindMismatch,
+    /// A frame announced a length bigger than the allowed maximum.
+    FrameTooLarge(u32),
+    /// The peer streamed more content than the allowed maximum.
+    StreamTooLong(u64),
+    /// The stream was closed before the expected frame arrived.
+    UnexpectedEnd,
+    /// The peer sent a malformed ephemeral key.
+    BadKey,
+    /// An encrypted frame failed to decrypt, either it was tampered with or the keys don't match.
+    Decryption,
+    /// A compressed frame failed to decompress.
+    Decompression,
+    /// An underlying IO error.
+    Io(std::io::Error),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::BadMagic(magic) => {
+                write!(
+                    f,
+                    "The peer isn't speaking the raven protocol (magic {:?})",
+                    magic
+                )
+            }
+            ProtocolError::UnsupportedVersion { theirs, ours } => write!(
+                f,
+                "The peer speaks raven protocol v{}, but this client only speaks v{}",
+                theirs, ours
+            ),
+            ProtocolError::UnknownKind(kind) => write!(f, "Unknown raven message type {}", kind),
+            ProtocolError::KindMismatch => {
+                write!(f, "The raven doesn't match the message type in the header")
+            }
+            ProtocolError::FrameTooLarge(len) => write!(f, "Frame of {} bytes is too large", len),
+            ProtocolError::StreamTooLong(max) => {
+                write!(f, "The peer streamed more than the {} bytes allowed", max)
+            }
+            ProtocolError::UnexpectedEnd => write!(f, "The connection was closed mid raven"),
+            ProtocolError::BadKey => write!(f, "The peer sent a malformed ephemeral key"),
+            ProtocolError::Decryption => write!(
+                f,
+                "A frame failed to decrypt, it may have been tampered with"
+            ),
+            ProtocolError::Decompression => write!(f, "A frame failed to decompress"),
+            ProtocolError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            ProtocolError::UnexpectedEnd
+        } else {
+            ProtocolError::Io(e)
+        }
+    }
+}
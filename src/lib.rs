@@ -1,5 +1,11 @@
 pub mod cli;
+pub mod clipboard;
 pub mod config;
-mod error;
+pub mod control;
+pub mod discovery;
+pub mod error;
+pub mod output;
+pub mod peers;
 pub mod raven;
-pub mod util;
\ No newline at end of file
+pub mod trust;
+pub mod util;
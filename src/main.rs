@@ -1,14 +1,87 @@
-use anyhow::Result;
+use std::io::IsTerminal;
+
+use anyhow::{bail, Result};
 use clap::Parser;
-use rv_raven::{cli::{Cli, Subcommands}, config::Config, raven::{mailbox, send}};
+use rv_raven::{
+    cli::{Cli, ClipSubcommands, Subcommands},
+    clipboard::Clipboard,
+    config::Config,
+    control, peers,
+    raven::{mailbox, send},
+    trust::{self, Identity},
+};
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let config = Config::load()?;
+    let output = cli.output;
+    let mut config = Config::load()?;
+    let identity = Identity::load(&config)?;
 
     match cli.commands {
-        Subcommands::Send { to, port, message } => send::send(&to, port, message),
-        Subcommands::SendFile { to, port, file } => send::send_file(&to, port, file),
-        Subcommands::Mailbox { commands } => mailbox::manage(commands, config),
+        Subcommands::Send {
+            to,
+            port,
+            message,
+            name,
+            compress,
+            no_compress,
+        } => {
+            let to = peers::resolve(&to, port, &config)?;
+            to.follow(&mut config);
+            config.sender.compress = (config.sender.compress || compress) && !no_compress;
+            let sent = match message {
+                Some(message) if message != "-" => send::send(&to, message, &config, &identity)?,
+                None if std::io::stdin().is_terminal() => {
+                    bail!("Nothing to send, give a MESSAGE or pipe it to stdin")
+                }
+                _ => send::send_stdin(&to, name, &config, &identity)?,
+            };
+            output.print(&sent, || println!("{}", sent))
+        }
+        Subcommands::SendFile {
+            to,
+            port,
+            mut files,
+            recursive,
+            compress,
+            no_compress,
+        } => {
+            let to = peers::resolve(&to, port, &config)?;
+            to.follow(&mut config);
+            config.sender.compress = (config.sender.compress || compress) && !no_compress;
+            if files.len() > 1 || send::is_pattern(&files[0]) {
+                let batch = send::send_batch(&to, files, &config, &identity)?;
+                output.print(&batch, || println!("{}", batch))?;
+                if !batch.failed.is_empty() {
+                    bail!(
+                        "{} of {} files couldn't be sent",
+                        batch.failed.len(),
+                        batch.sent.len() + batch.failed.len()
+                    );
+                }
+                return Ok(());
+            }
+
+            let file = files.remove(0);
+            let sent = if recursive && std::path::Path::new(&file).is_dir() {
+                send::send_dir(&to, file, &config, &identity)?
+            } else {
+                send::send_file(&to, file, &config, &identity)?
+            };
+            output.print(&sent, || println!("{}", sent))
+        }
+        Subcommands::Mailbox { commands } => mailbox::manage(commands, config, output),
+        Subcommands::Trust { commands } => trust::manage(commands, config, output),
+        Subcommands::Daemon { commands } => control::manage(commands, config, output),
+        Subcommands::Clip {
+            commands: ClipSubcommands::Send { to, port, mime },
+        } => {
+            let to = peers::resolve(&to, port, &config)?;
+            to.follow(&mut config);
+            let text = Clipboard::new(&config)?.paste(&mime)?;
+            let sent = send::send_clipboard(&to, text, mime, &config, &identity)?;
+            output.print(&sent, || println!("{}", sent))
+        }
+        Subcommands::Peers { commands } => peers::manage(commands, config, output),
     }
 }
@@ -0,0 +1,409 @@
+use std::fmt::Display;
+
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::{
+    control::Status,
+    raven::{compression::Compression, protocol::Kind},
+    util,
+};
+
+/// How `rv` prints what it did: for people, or as structured records for scripts.
+///
+/// The field names of the records are part of the interface of `rv`, they are only ever added to, never renamed.
+/// Dates are RFC 3339 in utc and optional fields are left out when they have no value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    #[default]
+    Text,
+    Json,
+    Toml,
+}
+
+impl Output {
+    /// Prints `record` as JSON or TOML, or calls `text` to print it for people.
+    pub fn print<T: Serialize>(self, record: &T, text: impl FnOnce()) -> Result<()> {
+        match self {
+            Output::Text => text(),
+            Output::Json => println!("{}", serde_json::to_string_pretty(record)?),
+            Output::Toml => print!("{}", toml::to_string(record)?),
+        }
+
+        Ok(())
+    }
+
+    /// Prints `record` as one of many arriving over time: a single line of JSON,
+    /// or a TOML document followed by an empty line.
+    pub fn stream<T: Serialize>(self, record: &T, text: impl FnOnce()) -> Result<()> {
+        match self {
+            Output::Text => text(),
+            Output::Json => println!("{}", serde_json::to_string(record)?),
+            Output::Toml => println!("{}", toml::to_string(record)?),
+        }
+
+        Ok(())
+    }
+}
+
+/// A raven delivered by `rv send` or `rv send-file`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Sent {
+    pub to: String,
+    pub port: u16,
+    pub kind: Kind,
+    /// The name of the file or of the directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The size in bytes of the text, of the file or of the files of the directory.
+    pub size: u64,
+    /// The blake3 hash of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// The MIME type of a clipboard.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+    /// The byte a file transfer was resumed from, 0 if it started from scratch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resumed_from: Option<u64>,
+    pub encrypted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
+}
+
+impl Display for Sent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.kind, &self.name, &self.mime) {
+            (Kind::Directory, Some(name), _) => write!(f, "Directory {} sent", name)?,
+            (_, Some(name), _) => write!(f, "File {} sent", name)?,
+            (_, None, Some(mime)) => write!(f, "Clipboard ({}) sent", mime)?,
+            (_, None, None) => write!(f, "Message sent")?,
+        }
+
+        write!(
+            f,
+            " to {} ({} bytes",
+            util::fmt_address(&self.to, self.port),
+            self.size
+        )?;
+        if self.encrypted {
+            write!(f, ", encrypted")?;
+        }
+        if let Some(compression) = self.compression {
+            write!(f, ", {} compressed", compression)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// The files delivered by `rv send-file` when many of them are sent together, and the ones that weren't.
+#[derive(Debug, Clone, Serialize)]
+pub struct BatchRecord {
+    pub to: String,
+    pub port: u16,
+    /// The id the files are kept with in the mailbox of the receiver.
+    pub batch: String,
+    pub sent: Vec<Sent>,
+    pub failed: Vec<FailedRecord>,
+}
+
+/// A file that couldn't be sent, as it was given to `rv send-file`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedRecord {
+    pub file: String,
+    pub reason: String,
+}
+
+impl Display for BatchRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for sent in &self.sent {
+            writeln!(f, "{}", sent)?;
+        }
+        for failed in &self.failed {
+            writeln!(f, "Failed to send {}: {}", failed.file, failed.reason)?;
+        }
+
+        write!(
+            f,
+            "Sent {} of {} files to {} ({} bytes, batch {})",
+            self.sent.len(),
+            self.sent.len() + self.failed.len(),
+            util::fmt_address(&self.to, self.port),
+            self.sent.iter().map(|sent| sent.size).sum::<u64>(),
+            self.batch
+        )
+    }
+}
+
+/// A message in the mailbox.
+#[derive(Debug, Clone, Serialize)]
+pub struct MessageRecord {
+    pub id: String,
+    pub from: String,
+    pub address: String,
+    pub when: String,
+    pub read: bool,
+    pub text: String,
+    /// The MIME type of a message that came from a clipboard.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime: Option<String>,
+}
+
+/// A file in the mailbox.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileRecord {
+    pub id: String,
+    pub from: String,
+    pub address: String,
+    pub when: String,
+    pub read: bool,
+    /// Where the file is stored.
+    pub path: String,
+    /// The id of the batch the file arrived with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+    /// The blake3 hash of the file, or the digest of the directory, when it arrived.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// Whether a received file is still what arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Integrity {
+    Intact,
+    Modified,
+    Missing,
+    Unreadable,
+    /// No hash was stored, the file arrived with an older version.
+    Unknown,
+}
+
+impl Display for Integrity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Integrity::Intact => write!(f, "intact"),
+            Integrity::Modified => write!(f, "modified since it arrived"),
+            Integrity::Missing => write!(f, "missing"),
+            Integrity::Unreadable => write!(f, "unreadable"),
+            Integrity::Unknown => write!(f, "no hash stored to check it"),
+        }
+    }
+}
+
+/// A file checked by `rv mailbox verify`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckRecord {
+    pub id: String,
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    pub integrity: Integrity,
+}
+
+/// The files checked by `rv mailbox verify`.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyRecord {
+    pub files: Vec<CheckRecord>,
+}
+
+/// A file transfer that didn't finish yet.
+#[derive(Debug, Clone, Serialize)]
+pub struct PartialRecord {
+    pub id: String,
+    pub from: String,
+    pub address: String,
+    pub when: String,
+    pub name: String,
+    pub size: u64,
+    /// How many bytes of the file arrived so far.
+    pub received: u64,
+}
+
+/// A raven that was refused.
+#[derive(Debug, Clone, Serialize)]
+pub struct RejectionRecord {
+    pub from: String,
+    pub address: String,
+    pub when: String,
+    pub kind: Kind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub size: u64,
+    pub reason: String,
+}
+
+/// The entries listed by `rv mailbox list` and `rv mailbox search`, the kinds that weren't listed are empty.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MailboxRecord {
+    pub messages: Vec<MessageRecord>,
+    pub files: Vec<FileRecord>,
+    pub partials: Vec<PartialRecord>,
+    pub rejections: Vec<RejectionRecord>,
+    /// The files moved to the trash, with the path they had.
+    pub trash: Vec<FileRecord>,
+}
+
+/// A single entry, shown by `rv mailbox show` or arriving while `rv mailbox watch` runs.
+/// Its `type` tells which kind of entry it is.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum EntryRecord {
+    Message(MessageRecord),
+    File(FileRecord),
+    Rejection(RejectionRecord),
+}
+
+/// A peer listed by `rv trust list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerRecord {
+    pub name: String,
+    pub fingerprint: String,
+    pub key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    pub since: String,
+    /// Whether the clipboards it sends are put on the clipboard right away.
+    pub clipboard: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeersRecord {
+    pub peers: Vec<PeerRecord>,
+}
+
+/// A device that answered on the LAN, listed by `rv peers`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceRecord {
+    pub name: String,
+    pub address: String,
+    /// The port where its ravens arrive.
+    pub port: u16,
+    pub fingerprint: String,
+    /// The protocol version it speaks.
+    pub version: u16,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DevicesRecord {
+    pub devices: Vec<DeviceRecord>,
+}
+
+/// A device of the address book, listed by `rv peers list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct BookPeerRecord {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+    /// Whether the ravens sent to it are compressed, the `sender.compress` config applies if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compress: Option<bool>,
+    /// Whether the ravens sent to it are encrypted, the `sender.encrypt` config applies if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encrypt: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AddressBookRecord {
+    pub peers: Vec<BookPeerRecord>,
+}
+
+/// The identity of this client, shown by `rv trust fingerprint`.
+#[derive(Debug, Clone, Serialize)]
+pub struct IdentityRecord {
+    pub fingerprint: String,
+    pub key: String,
+}
+
+/// What `rvd` answered to `rv daemon`, its `state` tells which answer it is.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum DaemonRecord {
+    Running(Status),
+    Stopping { transfers: usize },
+    Reloaded,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records() {
+        let entry = EntryRecord::File(FileRecord {
+            id: "0123456789abcdef".into(),
+            from: "laptop".into(),
+            address: "192.168.1.2".into(),
+            when: "2024-07-01T10:00:00Z".into(),
+            read: false,
+            path: "/home/raven/.raven/data/notes.txt".into(),
+            batch: None,
+            hash: None,
+        });
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["type"], "file");
+        assert_eq!(json["id"], "0123456789abcdef");
+        assert_eq!(json["when"], "2024-07-01T10:00:00Z");
+        assert!(toml::to_string(&entry).unwrap().contains("type = \"file\""));
+
+        let sent = Sent {
+            to: "192.168.1.3".into(),
+            port: 12345,
+            kind: Kind::Text,
+            name: None,
+            size: 4,
+            hash: None,
+            mime: None,
+            resumed_from: None,
+            encrypted: true,
+            compression: Some(Compression::Zstd),
+        };
+        let json = serde_json::to_value(&sent).unwrap();
+        assert_eq!(json["kind"], "text");
+        assert_eq!(json["compression"], "zstd");
+        assert!(json.get("name").is_none());
+        assert!(toml::to_string(&sent).is_ok());
+
+        let batch = BatchRecord {
+            to: "192.168.1.3".into(),
+            port: 12345,
+            batch: "0123456789abcdef".into(),
+            sent: vec![sent.clone()],
+            failed: vec![FailedRecord {
+                file: "missing.txt".into(),
+                reason: "No such file or directory".into(),
+            }],
+        };
+        let json = serde_json::to_value(&batch).unwrap();
+        assert_eq!(json["sent"][0]["kind"], "text");
+        assert_eq!(json["failed"][0]["file"], "missing.txt");
+        assert!(batch
+            .to_string()
+            .ends_with("Sent 1 of 2 files to 192.168.1.3:12345 (4 bytes, batch 0123456789abcdef)"));
+
+        let listing = MailboxRecord {
+            rejections: vec![RejectionRecord {
+                from: "phone".into(),
+                address: "192.168.1.4".into(),
+                when: "2024-07-01T10:00:00Z".into(),
+                kind: Kind::File,
+                name: Some("huge.bin".into()),
+                size: 1 << 30,
+                reason: "the raven is too large (max 1024 bytes)".into(),
+            }],
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&listing).unwrap();
+        assert_eq!(json["messages"], serde_json::json!([]));
+        assert_eq!(json["rejections"][0]["kind"], "file");
+        assert!(toml::to_string(&listing).is_ok());
+
+        let status = DaemonRecord::Stopping { transfers: 2 };
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["state"], "stopping");
+        assert_eq!(json["transfers"], 2);
+    }
+}
@@ -0,0 +1,411 @@
+use std::{
+    fmt::Display,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    cli::PeersSubcommands,
+    config::{Config, Peer},
+    discovery,
+    output::{AddressBookRecord, BookPeerRecord, Output},
+    util,
+};
+
+/// Where a raven goes, as `resolve` found it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    /// The ip addresses it may be reached at, tried in order.
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    /// The peer of the address book it is, by its name. If it has a fingerprint, the key of the receiver must match it.
+    pub peer: Option<(String, Peer)>,
+    /// The name and the fingerprint announced by the device found on the LAN it is, the key of the receiver must match it.
+    pub device: Option<(String, String)>,
+}
+
+impl Destination {
+    /// A destination at `ip` and `port`, that isn't a peer of the address book nor a device found on the LAN.
+    pub fn new(ip: IpAddr, port: u16) -> Self {
+        Destination::at(vec![ip], port)
+    }
+
+    /// A destination reached at any of the `addresses` and `port`.
+    fn at(addresses: Vec<IpAddr>, port: u16) -> Self {
+        Destination {
+            addresses,
+            port,
+            peer: None,
+            device: None,
+        }
+    }
+
+    /// Makes the ravens going to the destination follow the defaults of its peer of the address book, if it's one:
+    /// they replace the ones in `config.sender`.
+    pub fn follow(&self, config: &mut Config) {
+        if let Some((_, peer)) = &self.peer {
+            config.sender.compress = peer.compress.unwrap_or(config.sender.compress);
+            config.sender.encrypt = peer.encrypt.unwrap_or(config.sender.encrypt);
+        }
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let addresses = self
+            .addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, self.port).to_string())
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", addresses.join(" or "))
+    }
+}
+
+/// Finds where a raven goes. `to` is an ip address, v4 or v6, a hostname, or the name of a peer of the address book
+/// or of a device answering on the LAN, looked for in this order. It may end with `:port`, or `]:port` after an ipv6
+/// address between brackets, otherwise addresses and hostnames are reached on `port`, peers and devices on their own.
+///
+/// A raven sent to a peer of the address book, by its name or at its address, or to a device found on the LAN
+/// goes to a destination that tells which one, so the key of the receiver is checked once connected.
+pub fn resolve(to: &str, port: u16, config: &Config) -> Result<Destination> {
+    let (host, explicit) = split_port(to)?;
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        let mut destination = Destination::new(ip.to_canonical(), explicit.unwrap_or(port));
+        destination.peer = config
+            .peers
+            .iter()
+            .find(|(_, peer)| peer.port == destination.port && peer.is_at(ip))
+            .map(|(name, peer)| (name.clone(), peer.clone()));
+
+        return Ok(destination);
+    }
+
+    if let Some((name, peer)) = config.peer(host) {
+        let port = explicit.unwrap_or(peer.port);
+        let addresses = lookup(&peer.address, port).context(format!(
+            "Resolving `{}`, the address of the peer {} of the address book",
+            peer.address, name
+        ))?;
+        let mut destination = Destination::at(addresses, port);
+        destination.peer = Some((name.clone(), peer.clone()));
+
+        return Ok(destination);
+    }
+
+    let dns = match lookup(host, explicit.unwrap_or(port)) {
+        Ok(addresses) => return Ok(Destination::at(addresses, explicit.unwrap_or(port))),
+        Err(e) => e,
+    };
+    let lan = match discovery::find(host, config) {
+        Ok(device) => {
+            let ip = device
+                .address
+                .parse::<IpAddr>()
+                .context(format!("Invalid ip address {}", device.address))?;
+            let mut destination = Destination::new(ip, explicit.unwrap_or(device.beacon.port));
+            destination.device = Some((device.beacon.name, device.beacon.fingerprint));
+
+            return Ok(destination);
+        }
+        Err(e) => e,
+    };
+
+    bail!(
+        "`{}` isn't an ip address nor a peer of the address book, its dns lookup failed ({:#}) and so did looking for it on the LAN ({:#})",
+        host,
+        dns,
+        lan
+    )
+}
+
+/// Splits the port off `to`, if it has one. An ipv6 address may only have a port between brackets, `[::1]:4000`.
+fn split_port(to: &str) -> Result<(&str, Option<u16>)> {
+    let (host, port) = match to.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, rest)) => match rest.strip_prefix(':') {
+                Some(port) => (host, Some(port)),
+                None => bail!(
+                    "`{}` must be followed by a port, as in `[{}]:PORT`",
+                    to,
+                    host
+                ),
+            },
+            None => bail!("`{}` has no closing bracket", to),
+        },
+        // More than a colon is an ipv6 address without a port
+        None => match to.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (to, None),
+        },
+    };
+
+    if host.is_empty() {
+        bail!("`{}` has no address before its port", to);
+    }
+
+    let port = port
+        .map(|port| match port.parse::<u16>() {
+            Ok(port) if port != 0 => Ok(port),
+            _ => Err(anyhow!(
+                "The port `{}` of `{}` isn't a number between 1 and 65535",
+                port,
+                to
+            )),
+        })
+        .transpose()?;
+
+    Ok((host, port))
+}
+
+/// Looks up the ip addresses of `host` in the dns, in the order it gives them. An ip address is taken as it is.
+fn lookup(host: &str, port: u16) -> Result<Vec<IpAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip.to_canonical()]);
+    }
+    if !util::is_hostname(host) {
+        bail!("`{}` isn't a valid hostname", host);
+    }
+
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for address in (host, port)
+        .to_socket_addrs()
+        .context(format!("Looking up `{}` in the dns", host))?
+    {
+        let ip = address.ip().to_canonical();
+        if !addresses.contains(&ip) {
+            addresses.push(ip);
+        }
+    }
+    if addresses.is_empty() {
+        bail!("The dns has no address for `{}`", host);
+    }
+    eprintln!(
+        "Resolved {} to {}",
+        host,
+        addresses
+            .iter()
+            .map(IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    Ok(addresses)
+}
+
+/// Turns a pair of `--flag` and `--no-flag` into a setting, `None` if neither was given.
+fn setting(on: bool, off: bool) -> Option<bool> {
+    match (on, off) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+fn listing(config: &Config) -> AddressBookRecord {
+    AddressBookRecord {
+        peers: config
+            .peers
+            .iter()
+            .map(|(name, peer)| BookPeerRecord {
+                name: name.clone(),
+                address: peer.address.clone(),
+                port: peer.port,
+                fingerprint: peer.fingerprint.clone(),
+                compress: peer.compress,
+                encrypt: peer.encrypt,
+            })
+            .collect(),
+    }
+}
+
+fn list(config: &Config) {
+    println!("Peers:");
+    for (name, peer) in config.peers.iter() {
+        let defaults = [
+            peer.compress.map(|compress| {
+                if compress {
+                    "compressed"
+                } else {
+                    "uncompressed"
+                }
+            }),
+            peer.encrypt
+                .map(|encrypt| if encrypt { "encrypted" } else { "unencrypted" }),
+        ];
+
+        println!(
+            "{} at {}{}{}",
+            name,
+            util::fmt_address(&peer.address, peer.port),
+            peer.fingerprint
+                .as_ref()
+                .map(|fingerprint| format!(" [{}]", fingerprint))
+                .unwrap_or_default(),
+            defaults
+                .iter()
+                .flatten()
+                .map(|default| format!(", {}", default))
+                .collect::<String>()
+        );
+    }
+}
+
+pub fn manage(command: Option<PeersSubcommands>, mut config: Config, output: Output) -> Result<()> {
+    let command = command.unwrap_or(PeersSubcommands::Discover { wait: None });
+
+    match command {
+        PeersSubcommands::Add {
+            name,
+            address,
+            port,
+            fingerprint,
+            compress,
+            no_compress,
+            encrypt,
+            no_encrypt,
+        } => {
+            if config.peers.contains_key(&name) {
+                bail!(
+                    "There is already a peer called `{}`, change it with `rv peers edit`",
+                    name
+                );
+            }
+
+            let peer = Peer {
+                address,
+                port,
+                fingerprint: fingerprint.map(|fingerprint| fingerprint.to_lowercase()),
+                compress: setting(compress, no_compress),
+                encrypt: setting(encrypt, no_encrypt),
+            };
+            peer.validate(&name)?;
+            config.peers.insert(name, peer);
+            config.save()?;
+        }
+        PeersSubcommands::Edit {
+            name,
+            address,
+            port,
+            fingerprint,
+            compress,
+            no_compress,
+            encrypt,
+            no_encrypt,
+        } => {
+            let Some(peer) = config.peers.get_mut(&name) else {
+                bail!("Peer `{}` not found", name);
+            };
+
+            if let Some(address) = address {
+                peer.address = address;
+            }
+            if let Some(port) = port {
+                peer.port = port;
+            }
+            if let Some(fingerprint) = fingerprint {
+                peer.fingerprint = Some(fingerprint.to_lowercase());
+            }
+            peer.compress = setting(compress, no_compress).or(peer.compress);
+            peer.encrypt = setting(encrypt, no_encrypt).or(peer.encrypt);
+
+            peer.validate(&name)?;
+            config.save()?;
+        }
+        PeersSubcommands::Remove { name } => {
+            if config.peers.remove(&name).is_none() {
+                bail!("Peer `{}` not found", name);
+            }
+            config.save()?;
+        }
+        PeersSubcommands::List => output.print(&listing(&config), || list(&config))?,
+        PeersSubcommands::Discover { wait } => discovery::discovered(wait, config, output)?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut config = Config::new();
+        config.sender.compress = true;
+        config.sender.encrypt = true;
+        let laptop = Peer {
+            address: "10.0.0.5".into(),
+            port: 4000,
+            fingerprint: None,
+            compress: Some(false),
+            encrypt: None,
+        };
+        config.peers.insert("laptop".into(), laptop.clone());
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+        let destination = resolve("10.0.0.9", 12345, &config).unwrap();
+        assert_eq!(destination, Destination::new(ip("10.0.0.9"), 12345));
+
+        let destination = resolve("Laptop", 12345, &config).unwrap();
+        assert_eq!(destination.addresses, vec![ip("10.0.0.5")]);
+        assert_eq!(destination.port, 4000);
+        assert_eq!(destination.peer, Some(("laptop".into(), laptop.clone())));
+        let mut following = config.clone();
+        destination.follow(&mut following);
+        assert!(!following.sender.compress);
+        assert!(following.sender.encrypt);
+
+        let destination = resolve("laptop:4001", 12345, &config).unwrap();
+        assert_eq!(destination.port, 4001);
+        assert_eq!(
+            resolve("[::1]:4000", 12345, &config).unwrap(),
+            Destination::new(ip("::1"), 4000)
+        );
+        assert_eq!(
+            resolve("fe80::1", 12345, &config).unwrap(),
+            Destination::new(ip("fe80::1"), 12345)
+        );
+        let localhost = resolve("localhost:4000", 12345, &config).unwrap();
+        assert_eq!(localhost.port, 4000);
+        assert!(localhost.addresses.contains(&ip("127.0.0.1")));
+
+        // A peer is followed at any of the addresses it has
+        config.peers.insert(
+            "nas".into(),
+            Peer {
+                address: "localhost".into(),
+                port: 4000,
+                fingerprint: None,
+                compress: None,
+                encrypt: Some(false),
+            },
+        );
+        let destination = resolve("127.0.0.1:4000", 12345, &config).unwrap();
+        assert_eq!(destination.addresses, vec![ip("127.0.0.1")]);
+        assert_eq!(destination.peer.as_ref().unwrap().0, "nas");
+        assert!(destination.device.is_none());
+    }
+
+    #[test]
+    fn test_split_port() {
+        assert_eq!(split_port("nas.local").unwrap(), ("nas.local", None));
+        assert_eq!(
+            split_port("10.0.0.5:4000").unwrap(),
+            ("10.0.0.5", Some(4000))
+        );
+        assert_eq!(split_port("::1").unwrap(), ("::1", None));
+        assert_eq!(split_port("[::1]").unwrap(), ("::1", None));
+        assert_eq!(split_port("[::1]:4000").unwrap(), ("::1", Some(4000)));
+
+        assert!(split_port("nas.local:0").is_err());
+        assert!(split_port("nas.local:http").is_err());
+        assert!(split_port("nas.local:70000").is_err());
+        assert!(split_port(":4000").is_err());
+        assert!(split_port("[::1").is_err());
+        assert!(split_port("[::1]4000").is_err());
+    }
+}
@@ -1,25 +1,218 @@
+use std::fmt::Display;
+
+use compression::Compression;
+use crypto::Role;
+use directory::DirEntry;
+use protocol::Kind;
 use serde::{Deserialize, Serialize};
 
+pub mod compression;
+pub mod crypto;
+pub mod directory;
 pub mod mailbox;
+pub mod protocol;
+pub mod quota;
 pub mod receive;
 pub mod send;
 
 /// The raven is the message that the client will send or receive.
-/// It can be both a text message or a file.
+/// It can be a text message, a file, a batch of files, a directory or the content of a clipboard.
 ///
-/// TODO:
-/// - Trust: used to stablish a trusthy connection between the clients.  
-/// - Helo: used before sending a message to check if the target will accept it.
+/// Before a raven is sent, a `Helo` is used to check if the target will accept it,
+/// both ends prove their identities and, optionally, agree on the keys to encrypt the raven.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Raven {
     /// A text message
     Text { text: String },
-    /// A file with the name and the content as a byte stream
-    File { name: String, content: Vec<u8> },
+    /// A file with the name, the size and the blake3 hash of its content.
+    /// The content itself follows the raven as a stream of frames.
+    ///
+    /// The `id` identifies the transfer, so an interrupted one can be resumed when the same file is sent again.
+    File {
+        id: String,
+        name: String,
+        size: u64,
+        hash: String,
+    },
+    /// The text content of a clipboard and its MIME type, the receiver may put it on its own clipboard.
+    Clipboard { text: String, mime: String },
+    /// A directory with its name, the total size of its files and the folders and files inside it.
+    /// The content of the files follows the raven, one stream per file.
+    Directory {
+        name: String,
+        size: u64,
+        entries: Vec<DirEntry>,
+    },
+    /// Many files sent together, all of them kept in the mailbox with the batch `id`.
+    /// Each file is resumed, streamed and confirmed in turn, just like a file raven.
+    Batch { id: String, files: Vec<BatchFile> },
+}
+
+/// A file of a batch raven, with the same fields as a file raven.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchFile {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    pub hash: String,
 }
 
+impl Raven {
+    /// The message type announced in the protocol header for this raven.
+    pub fn kind(&self) -> Kind {
+        match self {
+            Raven::Text { .. } => Kind::Text,
+            Raven::File { .. } => Kind::File,
+            Raven::Clipboard { .. } => Kind::Clipboard,
+            Raven::Directory { .. } => Kind::Directory,
+            Raven::Batch { .. } => Kind::Batch,
+        }
+    }
+}
 
+/// Announces a raven before it's sent, so the receiver can decide whether to accept it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Helo {
+    /// The type of the raven.
+    pub kind: Kind,
+    /// The name of the file or of the directory, if the raven carries one.
+    pub name: Option<String>,
+    /// The size in bytes of the content of the raven.
+    pub size: u64,
+    /// The name of the sending device.
+    pub from: String,
+    /// The hex encoded public key of the sender, it must prove to own it answering a `SysRaven::Challenge`.
+    pub key: String,
+    /// The hex encoded ephemeral x25519 key of the sender, when it wants the raven to be encrypted.
+    pub ephemeral: Option<String>,
+    /// The compressions the sender offers for the raven, by order of preference, none if it shouldn't be compressed.
+    pub compression: Vec<Compression>,
+}
+
+impl Helo {
+    /// Whether `rv` is the raven this `Helo` announced.
+    pub fn announces(&self, rv: &Raven) -> bool {
+        match rv {
+            Raven::Text { text } => self.kind == Kind::Text && self.size == text.len() as u64,
+            Raven::File { name, size, .. } => {
+                self.kind == Kind::File
+                    && self.size == *size
+                    && self.name.as_deref() == Some(name.as_str())
+            }
+            Raven::Clipboard { text, .. } => {
+                self.kind == Kind::Clipboard && self.size == text.len() as u64
+            }
+            Raven::Directory { name, size, .. } => {
+                self.kind == Kind::Directory
+                    && self.size == *size
+                    && self.name.as_deref() == Some(name.as_str())
+            }
+            Raven::Batch { files, .. } => {
+                self.kind == Kind::Batch
+                    && self.size == files.iter().map(|file| file.size).sum::<u64>()
+            }
+        }
+    }
+}
+
+/// Everything both ends said during the handshake: the `helo` and the `nonce`, `key` and `ephemeral` key of the challenge.
+/// Each end signs it to prove its identity and the encryption keys are bound to it, so it can't be tampered with or replayed.
+pub fn transcript(helo: &Helo, nonce: &[u8], key: &str, ephemeral: Option<&str>) -> Vec<u8> {
+    let mut transcript = bincode::serialize(helo).unwrap_or_default();
+    transcript.extend(nonce);
+    transcript.extend(key.as_bytes());
+    transcript.extend(ephemeral.unwrap_or_default().as_bytes());
+
+    transcript
+}
+
+/// The message signed by the given side of the conversation to prove its identity.
+pub fn signed(role: Role, transcript: &[u8]) -> Vec<u8> {
+    let mut message = match role {
+        Role::Sender => b"raven-proof".to_vec(),
+        Role::Receiver => b"raven-challenge".to_vec(),
+    };
+    message.extend(transcript);
+
+    message
+}
+
+/// Why a receiver refused a raven.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Rejection {
+    /// The raven is bigger than the receiver allows.
+    TooLarge { max: u64 },
+    /// The receiver doesn't trust the sender.
+    Untrusted,
+    /// The receiver has no room to store the raven.
+    DiskFull,
+    /// The receiver doesn't accept ravens of this type.
+    KindNotAllowed(Kind),
+    /// The receiver only accepts encrypted ravens.
+    Unencrypted,
+    /// The sender already sent as much as it may in a day.
+    DailyQuota { max: u64 },
+    /// The receiver storage for files is full.
+    StorageFull { max: u64 },
+    /// The name of the file or of the directory can't be used as a filename.
+    UnsafeName(String),
+    /// What arrived doesn't match the hash of what was sent, it was quarantined.
+    Corrupted,
+    /// The MIME type of the clipboard isn't a `type/subtype` MIME type.
+    InvalidMime(String),
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Rejection::TooLarge { max } => write!(f, "the raven is too large (max {} bytes)", max),
+            Rejection::Untrusted => write!(f, "the sender isn't trusted"),
+            Rejection::DiskFull => write!(f, "there is no room left on the disk"),
+            Rejection::KindNotAllowed(kind) => write!(f, "{} ravens aren't allowed", kind),
+            Rejection::Unencrypted => write!(f, "only encrypted ravens are accepted"),
+            Rejection::DailyQuota { max } => {
+                write!(f, "the sender exceeded its daily quota (max {} bytes)", max)
+            }
+            Rejection::StorageFull { max } => {
+                write!(f, "the receiver storage is full (max {} bytes)", max)
+            }
+            Rejection::UnsafeName(name) => write!(f, "the name {:?} isn't allowed", name),
+            Rejection::Corrupted => write!(f, "the content that arrived doesn't match its hash"),
+            Rejection::InvalidMime(mime) => write!(f, "{:?} isn't a MIME type", mime),
+        }
+    }
+}
+
+/// Messages exchanged between the clients to coordinate the delivery of a raven.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SysRaven {
-    
-}
\ No newline at end of file
+    /// Sent by the sender before the raven.
+    Helo(Helo),
+    /// Sent by the receiver after a `Helo`: a random nonce the sender must sign, the receiver public key
+    /// and its ephemeral key if the sender asked for encryption.
+    /// The receiver signs the `transcript` of the handshake to prove it owns `key`.
+    Challenge {
+        nonce: Vec<u8>,
+        key: String,
+        ephemeral: Option<String>,
+        signature: Vec<u8>,
+    },
+    /// Sent by the sender: its signature of the `transcript` of the handshake.
+    Proof { signature: Vec<u8> },
+    /// Sent by the receiver when it accepts the announced raven, with the compression it picked from the offered ones.
+    /// Every frame after it is compressed with it.
+    Accept { compression: Option<Compression> },
+    /// Sent by the receiver when it refuses the announced raven.
+    Reject(Rejection),
+    /// Sent by the receiver of a file raven: how many bytes of the content it already holds.
+    /// The sender must stream the content starting from this offset.
+    ///
+    /// In a batch it's sent for every file, unless the file is refused with a `Reject` and skipped.
+    Resume { offset: u64 },
+    /// Sent by the sender after the content of every file: the blake3 hash of the whole file,
+    /// computed while streaming it. The receiver checks it against the hash of what arrived.
+    Trailer { hash: String },
+    /// Sent by the receiver once a raven is stored, in a batch before the next file is resumed.
+    /// A `Reject` is sent instead if what arrived doesn't match its hash.
+    Stored,
+}
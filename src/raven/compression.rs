@@ -0,0 +1,166 @@
+use std::{fmt::Display, io::Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::ProtocolError;
+
+/// The compression algorithms this client speaks, by order of preference.
+pub const SUPPORTED: [Compression; 2] = [Compression::Zstd, Compression::Deflate];
+
+/// The zstd level used to compress, favouring speed since the content is compressed while it's streamed.
+const ZSTD_LEVEL: i32 = 3;
+
+/// File extensions of formats that are already compressed, compressing them again only wastes time.
+const COMPRESSED_EXTENSIONS: [&str; 30] = [
+    "7z", "apk", "avi", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz", "heic", "jar",
+    "jpeg", "jpg", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "png", "rar", "rpm",
+    "tgz", "webm", "webp", "xz",
+];
+
+/// Magic bytes of formats that are already compressed, for files without a telling extension.
+const COMPRESSED_MAGIC: [&[u8]; 8] = [
+    b"\x1f\x8b",           // gzip
+    b"\x28\xb5\x2f\xfd",   // zstd
+    b"\xfd7zXZ\x00",       // xz
+    b"BZh",                // bzip2
+    b"PK\x03\x04",         // zip
+    b"7z\xbc\xaf\x27\x1c", // 7z
+    b"\x89PNG",            // png
+    b"\xff\xd8\xff",       // jpeg
+];
+
+/// How the frames of a conversation are compressed.
+///
+/// Every frame is compressed on its own, so the receiver decompresses the content as it streams it to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Zstd,
+    Deflate,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::Zstd => write!(f, "zstd"),
+            Compression::Deflate => write!(f, "deflate"),
+        }
+    }
+}
+
+impl Compression {
+    /// Picks the first of the compressions `offered` by the sender that this client speaks.
+    pub fn negotiate(offered: &[Compression]) -> Option<Compression> {
+        offered
+            .iter()
+            .find(|compression| SUPPORTED.contains(compression))
+            .copied()
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        match self {
+            Compression::Zstd => Ok(zstd::bulk::compress(data, ZSTD_LEVEL)?),
+            Compression::Deflate => {
+                let mut compressed = Vec::new();
+                flate2::read::DeflateEncoder::new(data, flate2::Compression::fast())
+                    .read_to_end(&mut compressed)?;
+
+                Ok(compressed)
+            }
+        }
+    }
+
+    /// Decompresses a frame, failing if it holds more than `max` bytes once decompressed.
+    pub fn decompress(&self, data: &[u8], max: usize) -> Result<Vec<u8>, ProtocolError> {
+        let mut decompressed = Vec::new();
+
+        match self {
+            Compression::Zstd => zstd::stream::read::Decoder::new(data)
+                .map_err(|_| ProtocolError::Decompression)?
+                .take(max as u64 + 1)
+                .read_to_end(&mut decompressed),
+            Compression::Deflate => flate2::read::DeflateDecoder::new(data)
+                .take(max as u64 + 1)
+                .read_to_end(&mut decompressed),
+        }
+        .map_err(|_| ProtocolError::Decompression)?;
+
+        if decompressed.len() > max {
+            return Err(ProtocolError::FrameTooLarge(decompressed.len() as u32));
+        }
+
+        Ok(decompressed)
+    }
+}
+
+/// Guesses whether the file at `path` is already compressed, by its extension or its first bytes.
+pub fn is_compressed(path: &str) -> bool {
+    let extension = std::path::Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    if let Some(extension) = extension {
+        if COMPRESSED_EXTENSIONS.contains(&extension.as_str()) {
+            return true;
+        }
+    }
+
+    let mut magic = [0u8; 8];
+    let read = std::fs::File::open(path)
+        .and_then(|f| f.take(magic.len() as u64).read(&mut magic))
+        .unwrap_or(0);
+
+    COMPRESSED_MAGIC
+        .iter()
+        .any(|known| read >= known.len() && magic.starts_with(known))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data = "raven ".repeat(1000);
+
+        for compression in SUPPORTED {
+            let compressed = compression.compress(data.as_bytes()).unwrap();
+            assert!(compressed.len() < data.len());
+            assert_eq!(
+                compression.decompress(&compressed, data.len()).unwrap(),
+                data.as_bytes()
+            );
+
+            // Decompressing more than allowed fails instead of filling the memory
+            assert!(compression.decompress(&compressed, data.len() - 1).is_err());
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            Compression::negotiate(&[Compression::Deflate, Compression::Zstd]),
+            Some(Compression::Deflate)
+        );
+        assert_eq!(Compression::negotiate(&[]), None);
+    }
+
+    #[test]
+    fn test_is_compressed() {
+        let dir =
+            std::env::temp_dir().join(format!("raven-test-compressed-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let archive = dir.join("archive.tar.gz");
+        let disguised = dir.join("archive");
+        let log = dir.join("raven.log");
+        std::fs::write(&archive, "").unwrap();
+        std::fs::write(&disguised, b"\x28\xb5\x2f\xfd rest of the frame").unwrap();
+        std::fs::write(&log, "plain text").unwrap();
+
+        assert!(is_compressed(archive.to_str().unwrap()));
+        assert!(is_compressed(disguised.to_str().unwrap()));
+        assert!(!is_compressed(log.to_str().unwrap()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,166 @@
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use rand_core::OsRng;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::error::ProtocolError;
+
+/// The side of the conversation a client is on, each direction is encrypted with its own key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// An ephemeral x25519 key generated for a single conversation.
+pub struct Ephemeral {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+/// Encrypts and decrypts the frames of a conversation with ChaCha20-Poly1305.
+///
+/// Every frame uses the next nonce of its direction, so frames can't be replayed, dropped or reordered unnoticed.
+pub struct Cipher {
+    seal: ChaCha20Poly1305,
+    open: ChaCha20Poly1305,
+    sealed: u64,
+    opened: u64,
+}
+
+impl Ephemeral {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+
+        Self { secret, public }
+    }
+
+    /// The hex encoded public key to send to the peer.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.public.as_bytes())
+    }
+
+    /// Agrees on the keys of the conversation with the peer ephemeral key `theirs`.
+    /// The keys are bound to the `transcript` of the handshake, so both ends must have seen the same handshake.
+    pub fn agree(
+        self,
+        theirs: &str,
+        transcript: &[u8],
+        role: Role,
+    ) -> Result<Cipher, ProtocolError> {
+        let theirs = hex::decode(theirs)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .ok_or(ProtocolError::BadKey)?;
+
+        let shared = self.secret.diffie_hellman(&PublicKey::from(theirs));
+        if !shared.was_contributory() {
+            return Err(ProtocolError::BadKey);
+        }
+
+        let mut material = shared.as_bytes().to_vec();
+        material.extend(blake3::hash(transcript).as_bytes());
+
+        let to_receiver = blake3::derive_key("raven 2024-07 sender to receiver", &material);
+        let to_sender = blake3::derive_key("raven 2024-07 receiver to sender", &material);
+
+        let (seal, open) = match role {
+            Role::Sender => (to_receiver, to_sender),
+            Role::Receiver => (to_sender, to_receiver),
+        };
+
+        Ok(Cipher {
+            seal: ChaCha20Poly1305::new(Key::from_slice(&seal)),
+            open: ChaCha20Poly1305::new(Key::from_slice(&open)),
+            sealed: 0,
+            opened: 0,
+        })
+    }
+}
+
+impl Default for Ephemeral {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cipher {
+    /// Encrypts a frame to be sent.
+    pub fn seal(&mut self, plain: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let nonce = nonce(self.sealed);
+        self.sealed += 1;
+
+        self.seal
+            .encrypt(Nonce::from_slice(&nonce), plain)
+            .map_err(|_| ProtocolError::Decryption)
+    }
+
+    /// Decrypts a received frame, failing if it was tampered with.
+    pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let nonce = nonce(self.opened);
+        self.opened += 1;
+
+        self.open
+            .decrypt(Nonce::from_slice(&nonce), sealed)
+            .map_err(|_| ProtocolError::Decryption)
+    }
+}
+
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+
+    nonce
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(transcript: &[u8], other: &[u8]) -> (Cipher, Cipher) {
+        let sender = Ephemeral::new();
+        let receiver = Ephemeral::new();
+        let (sender_key, receiver_key) = (sender.public_key(), receiver.public_key());
+
+        (
+            sender
+                .agree(&receiver_key, transcript, Role::Sender)
+                .unwrap(),
+            receiver.agree(&sender_key, other, Role::Receiver).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let (mut sender, mut receiver) = pair(b"handshake", b"handshake");
+
+        let first = sender.seal(b"first").unwrap();
+        let second = sender.seal(b"second").unwrap();
+        assert_ne!(&first[..], b"first");
+
+        assert_eq!(receiver.open(&first).unwrap(), b"first");
+        assert_eq!(receiver.open(&second).unwrap(), b"second");
+
+        let reply = receiver.seal(b"reply").unwrap();
+        assert_eq!(sender.open(&reply).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_tampering() {
+        let (mut sender, mut receiver) = pair(b"handshake", b"handshake");
+        let mut sealed = sender.seal(b"content").unwrap();
+        sealed[0] ^= 1;
+        assert!(receiver.open(&sealed).is_err());
+
+        // Replaying or reordering frames is noticed
+        let (mut sender, mut receiver) = pair(b"handshake", b"handshake");
+        let first = sender.seal(b"first").unwrap();
+        let second = sender.seal(b"second").unwrap();
+        assert!(receiver.open(&second).is_err());
+        assert!(receiver.open(&first).is_err());
+
+        // Both ends must have seen the same handshake
+        let (mut sender, mut receiver) = pair(b"handshake", b"tampered handshake");
+        assert!(receiver.open(&sender.seal(b"content").unwrap()).is_err());
+    }
+}
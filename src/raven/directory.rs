@@ -0,0 +1,394 @@
+use std::{
+    collections::HashSet,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    raven::{
+        protocol::{Channel, Hashed},
+        SysRaven,
+    },
+    util,
+};
+
+/// An entry of a directory raven: a folder or a file inside the directory sent.
+///
+/// The content of the files follows the raven, one stream per file in the order of the entries,
+/// each one followed by its `SysRaven::Trailer`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirEntry {
+    /// The path of the entry relative to the directory, with `/` as separator.
+    pub path: String,
+    /// Whether the entry is a folder.
+    pub folder: bool,
+    /// The size in bytes of a file, 0 for a folder.
+    pub size: u64,
+    /// The unix permissions of the entry, only the read, write and execute bits are kept.
+    pub mode: u32,
+    /// When the entry was last modified, in seconds since the unix epoch.
+    pub mtime: u64,
+}
+
+/// Lists the folders and files inside the directory at `root`, parents before their children.
+/// Symbolic links are skipped, so nothing outside the directory is ever sent.
+pub fn manifest(root: &str) -> Result<Vec<DirEntry>> {
+    fn walk(root: &Path, relative: &str, entries: &mut Vec<DirEntry>) -> Result<()> {
+        let folder = root.join(relative);
+        let mut children = std::fs::read_dir(&folder)
+            .context(format!("Reading the folder {}", folder.display()))?
+            .collect::<std::io::Result<Vec<_>>>()
+            .context(format!("Reading the folder {}", folder.display()))?;
+        children.sort_by_key(|child| child.file_name());
+
+        for child in children {
+            let name = child.file_name();
+            let Some(name) = name.to_str() else {
+                eprintln!("Skipping {}: its name isn't utf-8", child.path().display());
+                continue;
+            };
+            let path = if relative.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", relative, name)
+            };
+
+            let meta = std::fs::symlink_metadata(child.path())
+                .context(format!("Reading metadata of {}", child.path().display()))?;
+            if meta.file_type().is_symlink() {
+                eprintln!("Skipping the symbolic link {}", child.path().display());
+                continue;
+            }
+
+            entries.push(DirEntry {
+                path: path.clone(),
+                folder: meta.is_dir(),
+                size: if meta.is_dir() { 0 } else { meta.len() },
+                mode: mode(&meta),
+                mtime: meta
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0),
+            });
+
+            if meta.is_dir() {
+                walk(root, &path, entries)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    if !Path::new(root).is_dir() {
+        bail!("{} isn't a directory", root);
+    }
+
+    let mut entries = Vec::new();
+    walk(Path::new(root), "", &mut entries)?;
+
+    Ok(entries)
+}
+
+/// Whether `path` stays inside the folder it's relative to: no absolute paths, no `.` or `..` and nothing empty,
+/// and every component is a filename that `util::safe_filename` keeps as it is.
+pub fn is_safe(path: &str) -> bool {
+    path.split('/')
+        .all(|component| util::safe_filename(component).is_ok_and(|safe| safe == component))
+}
+
+/// Checks the entries of a directory raven before anything is written: every path must be safe,
+/// appear once and have its parent folder listed before it, and the files must add up to `size` bytes.
+pub fn check(entries: &[DirEntry], size: u64) -> Result<()> {
+    let mut folders = HashSet::new();
+    let mut seen = HashSet::new();
+
+    for entry in entries {
+        if !is_safe(&entry.path) {
+            bail!("The directory holds the unsafe path `{}`", entry.path);
+        }
+        if !seen.insert(entry.path.as_str()) {
+            bail!("The directory holds `{}` twice", entry.path);
+        }
+        if let Some((parent, _)) = entry.path.rsplit_once('/') {
+            if !folders.contains(parent) {
+                bail!("`{}` comes before its folder", entry.path);
+            }
+        }
+        if entry.folder {
+            folders.insert(entry.path.as_str());
+        }
+    }
+
+    let total = entries.iter().map(|entry| entry.size).sum::<u64>();
+    if total != size {
+        bail!(
+            "The files of the directory hold {} bytes, not {}",
+            total,
+            size
+        );
+    }
+
+    Ok(())
+}
+
+/// Streams the content of the files of the directory at `root`, one stream per file in the order of the entries,
+/// each one followed by the hash of what was streamed.
+pub fn stream<S: Read + Write>(
+    channel: &mut Channel<S>,
+    root: &str,
+    entries: &[DirEntry],
+) -> Result<()> {
+    for entry in entries.iter().filter(|entry| !entry.folder) {
+        let path = Path::new(root).join(&entry.path);
+        let f = std::fs::File::open(&path)
+            .context(format!("Opening file {} to be sent", path.display()))?;
+
+        // Never more than announced, even if the file grew meanwhile
+        let mut content = Hashed::new(BufReader::new(f).take(entry.size));
+        let sent = channel.write_stream(&mut content).context(format!(
+            "Streaming file {} to the TCP stream",
+            path.display()
+        ))?;
+        if sent != entry.size {
+            bail!("{} changed while it was being sent", path.display());
+        }
+
+        channel
+            .write_message(&SysRaven::Trailer {
+                hash: content.hash(),
+            })
+            .context("Writing the hash of the file")?;
+    }
+
+    Ok(())
+}
+
+/// Creates the directory announced by `entries` at `root` with the content streamed by the sender.
+/// The entries must have passed `check`.
+///
+/// Returns the paths of the files whose content doesn't match the hash the sender computed while streaming it.
+///
+/// The permissions and modification times are restored once the content is written,
+/// the ones of the folders last, since writing inside a folder changes its modification time.
+pub fn extract<S: Read + Write>(
+    channel: &mut Channel<S>,
+    root: &str,
+    entries: &[DirEntry],
+) -> Result<Vec<String>> {
+    std::fs::create_dir_all(root).context(format!("Creating the folder {}", root))?;
+    let mut corrupted = Vec::new();
+
+    for entry in entries {
+        let path = Path::new(root).join(&entry.path);
+
+        if entry.folder {
+            std::fs::create_dir(&path)
+                .context(format!("Creating the folder {}", path.display()))?;
+            continue;
+        }
+
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .context(format!("Creating the file {}", path.display()))?;
+        let mut w = Hashed::new(BufWriter::new(f));
+        let received = channel.read_stream(&mut w, entry.size)?;
+        if received != entry.size {
+            bail!(
+                "Expected {} bytes of {} but {} arrived",
+                entry.size,
+                entry.path,
+                received
+            );
+        }
+
+        match channel.read_message()? {
+            SysRaven::Trailer { hash } if hash == w.hash() => (),
+            SysRaven::Trailer { .. } => corrupted.push(entry.path.clone()),
+            other => bail!(
+                "Expected the hash of {} but {:?} arrived",
+                entry.path,
+                other
+            ),
+        }
+
+        let f = w.into_inner().into_inner().map_err(|e| e.into_error())?;
+        restore(&f, &path, entry)?;
+    }
+
+    for entry in entries.iter().rev().filter(|entry| entry.folder) {
+        let path = Path::new(root).join(&entry.path);
+        let f = std::fs::File::open(&path).context(format!("Opening {}", path.display()))?;
+        restore(&f, &path, entry)?;
+    }
+
+    Ok(corrupted)
+}
+
+/// Hashes the directory at `root`: the path of every folder and file inside it, and the content of the files.
+/// Anything added, removed, renamed or modified changes it.
+pub fn digest(root: &str) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+
+    for entry in manifest(root)? {
+        hasher.update(entry.path.as_bytes());
+        if entry.folder {
+            hasher.update(b"/");
+        } else {
+            let path = Path::new(root).join(&entry.path);
+            hasher.update(b"\0");
+            hasher.update(util::hash_file(path.to_str().unwrap_or_default())?.as_bytes());
+        }
+        hasher.update(b"\n");
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Sets the modification time and then the permissions of an extracted entry, opened as `f`.
+fn restore(f: &std::fs::File, path: &Path, entry: &DirEntry) -> Result<()> {
+    f.set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime))
+        .context(format!(
+            "Setting the modification time of {}",
+            path.display()
+        ))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(entry.mode & 0o777))
+            .context(format!("Setting the permissions of {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// The permissions of an entry, just read and write for the owner where there are no unix permissions.
+fn mode(meta: &std::fs::Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o777
+    }
+    #[cfg(not(unix))]
+    {
+        if meta.is_dir() {
+            0o755
+        } else {
+            0o644
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    #[cfg(unix)]
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use super::*;
+
+    #[test]
+    fn test_is_safe() {
+        assert!(is_safe("notes.txt"));
+        assert!(is_safe("photos/2024/beach.png"));
+        assert!(!is_safe(""));
+        assert!(!is_safe("/etc/passwd"));
+        assert!(!is_safe("../escape"));
+        assert!(!is_safe("photos/../../escape"));
+        assert!(!is_safe("./notes.txt"));
+        assert!(!is_safe("photos//beach.png"));
+        assert!(!is_safe("photos/"));
+        assert!(!is_safe("photos\\..\\..\\escape"));
+        assert!(!is_safe("photos/con"));
+        assert!(!is_safe("photos/bad\nname"));
+        assert!(!is_safe("photos/\0"));
+    }
+
+    #[test]
+    fn test_check() {
+        let entry = |path: &str, folder: bool, size: u64| DirEntry {
+            path: path.into(),
+            folder,
+            size,
+            mode: 0o644,
+            mtime: 0,
+        };
+
+        assert!(check(&[entry("a", true, 0), entry("a/b", false, 3)], 3).is_ok());
+        assert!(check(&[entry("a", true, 0), entry("a/b", false, 3)], 4).is_err());
+        assert!(check(&[entry("a/b", false, 3)], 3).is_err());
+        assert!(check(&[entry("b", false, 1), entry("b", false, 1)], 2).is_err());
+        assert!(check(&[entry("../b", false, 1)], 1).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("raven-test-directory-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let source = dir.join("source");
+        std::fs::create_dir_all(source.join("bin")).unwrap();
+        std::fs::create_dir_all(source.join("empty")).unwrap();
+        std::fs::write(source.join("notes.txt"), "notes").unwrap();
+        std::fs::write(source.join("bin/run.sh"), "#!/bin/sh\necho hi\n").unwrap();
+        #[cfg(unix)]
+        {
+            std::fs::set_permissions(
+                source.join("bin/run.sh"),
+                std::fs::Permissions::from_mode(0o750),
+            )
+            .unwrap();
+            std::os::unix::fs::symlink("/etc/passwd", source.join("passwd")).unwrap();
+        }
+        std::fs::File::options()
+            .write(true)
+            .open(source.join("notes.txt"))
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+            .unwrap();
+
+        let source = source.to_str().unwrap();
+        let entries = manifest(source).unwrap();
+        let paths = entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["bin", "bin/run.sh", "empty", "notes.txt"]);
+        check(&entries, 5 + 18).unwrap();
+
+        let mut sent = Channel::new(Cursor::new(Vec::new()));
+        stream(&mut sent, source, &entries).unwrap();
+
+        let target = dir.join("target");
+        let mut received = Channel::new(Cursor::new(sent.stream().get_ref().clone()));
+        let corrupted = extract(&mut received, target.to_str().unwrap(), &entries).unwrap();
+        assert!(corrupted.is_empty());
+        assert_eq!(
+            digest(target.to_str().unwrap()).unwrap(),
+            digest(source).unwrap()
+        );
+
+        assert_eq!(
+            std::fs::read_to_string(target.join("notes.txt")).unwrap(),
+            "notes"
+        );
+        assert!(target.join("empty").is_dir());
+        assert!(!target.join("passwd").exists());
+        #[cfg(unix)]
+        {
+            let run = std::fs::metadata(target.join("bin/run.sh")).unwrap();
+            assert_eq!(run.mode() & 0o777, 0o750);
+            let notes = std::fs::metadata(target.join("notes.txt")).unwrap();
+            assert_eq!(notes.mtime(), 1_000_000_000);
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
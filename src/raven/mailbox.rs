@@ -1,9 +1,29 @@
+use std::{
+    collections::HashSet,
+    io::{ErrorKind, IsTerminal, Write},
+    time::Duration,
+};
+
 use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Utc};
+use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use toml::value::Datetime;
 
-use crate::{cli::MailboxSubcommands, config::Config, util};
+use crate::{
+    cli::MailboxSubcommands,
+    config::{Config, PeerNames},
+    output::{
+        CheckRecord, EntryRecord, FileRecord, Integrity, MailboxRecord, MessageRecord, Output,
+        PartialRecord, RejectionRecord, VerifyRecord,
+    },
+    raven::{directory, protocol::Kind},
+    util,
+};
+
+mod filter;
+
+pub use filter::{Filter, Sort};
 
 /// The mailbox is the structure that holds the messages and files that the client has received.
 ///
@@ -12,27 +32,126 @@ use crate::{cli::MailboxSubcommands, config::Config, util};
 pub struct MailBox {
     messages: Vec<MailMessage>,
     files: Vec<MailFile>,
+    #[serde(default)]
+    partials: Vec<MailPartial>,
+    #[serde(default)]
+    rejections: Vec<MailRejection>,
+    #[serde(default)]
+    trash: Vec<MailTrashed>,
 }
 
 /// A message is a text message that the client has received.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct MailMessage {
+    /// Identifies the message for as long as it's in the mailbox, entries from older versions get one when opened.
+    #[serde(default)]
+    pub id: String,
+    /// The verified identity of the sender.
     pub from: String,
+    /// The address the raven came from.
+    #[serde(default)]
+    pub address: String,
     pub when: Datetime,
     pub text: String,
+    /// Whether the message was shown already.
+    #[serde(default)]
+    pub read: bool,
+    /// The MIME type of a message that came from a clipboard.
+    #[serde(default)]
+    pub mime: Option<String>,
 }
 
 /// A file is a file that the client has received.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct MailFile {
+    /// Identifies the file for as long as it's in the mailbox, entries from older versions get one when opened.
+    #[serde(default)]
+    pub id: String,
+    /// The verified identity of the sender.
+    pub from: String,
+    /// The address the raven came from.
+    #[serde(default)]
+    pub address: String,
+    pub when: Datetime,
+    pub name: String,
+    /// Whether the file was shown already.
+    #[serde(default)]
+    pub read: bool,
+    /// The id of the batch the file arrived with, if it was sent together with other files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch: Option<String>,
+    /// The blake3 hash of the content of the file, or the `directory::digest` of a directory, checked by `verify`.
+    /// Entries from older versions have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// A file transfer that started but didn't finish yet, it may be resumed by the sender.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MailPartial {
+    pub id: String,
+    pub hash: String,
     pub from: String,
+    #[serde(default)]
+    pub address: String,
     pub when: Datetime,
     pub name: String,
-    // TODO: Store the file hash to check when deleting
+    pub size: u64,
+    pub path: String,
 }
 
+/// A raven that was refused, kept so the user knows who tried to send what.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MailRejection {
+    pub from: String,
+    pub address: String,
+    pub when: Datetime,
+    pub kind: Kind,
+    pub name: Option<String>,
+    pub size: u64,
+    pub reason: String,
+}
+
+/// A received file moved to the trash folder by `delete --trash`, `restore` puts it back where it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MailTrashed {
+    /// Where the file is kept in the trash folder.
+    pub path: String,
+    /// The entry of the file as it was in the mailbox.
+    pub file: MailFile,
+}
+
+/// What `delete` does with the received file of an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disposal {
+    /// Removes the file, `force` even if it was modified since it arrived.
+    Delete { force: bool },
+    /// Leaves the file where it is, only the entry is deleted.
+    Keep,
+    /// Moves the file to the trash folder.
+    Trash,
+}
+
+/// An entry of the mailbox, found by its id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    Message(usize),
+    File(usize),
+}
+
+/// The folder in the raven home where `delete --trash` moves the received files.
+const TRASH_FOLDER: &str = "trash";
+
+/// How many random bytes make an id.
+const ID_LEN: usize = 8;
+/// How many characters of an id are shown when listing.
+const SHORT_ID_LEN: usize = 8;
+/// How many characters of an id must be given at least to find an entry.
+const MIN_ID_PREFIX: usize = 4;
+
 trait Summarizable {
-    fn summary(&self) -> String;
+    /// Summarizes the entry in a line, naming the sender after the peer of the address book at its address, if any.
+    fn summary(&self, peers: &PeerNames) -> String;
 }
 
 impl MailBox {
@@ -41,10 +160,24 @@ impl MailBox {
         Self {
             messages: Vec::new(),
             files: Vec::new(),
+            partials: Vec::new(),
+            rejections: Vec::new(),
+            trash: Vec::new(),
         }
     }
 
     pub fn open(config: &Config) -> Result<Self> {
+        let mailbox = Self::read(config)?;
+
+        // Entries from older versions have no id yet, they get one that is saved right away
+        if mailbox.needs_ids() {
+            return Self::update(config, |mailbox| mailbox.clone());
+        }
+
+        Ok(mailbox)
+    }
+
+    fn read(config: &Config) -> Result<Self> {
         if !std::path::Path::new(&format!("{}/mailbox.toml", config.raven_home)).exists() {
             return Ok(Self::new());
         }
@@ -53,10 +186,48 @@ impl MailBox {
         Ok(toml::from_str::<Self>(&content)?)
     }
 
-    pub fn save(&self, config: &Config) -> Result<()> {
-        let content = toml::to_string(self).context("Serializing the mailbox before saving")?;
+    /// Whether any entry has no id yet.
+    fn needs_ids(&self) -> bool {
+        self.messages.iter().any(|message| message.id.is_empty())
+            || self.files.iter().any(|file| file.id.is_empty())
+    }
+
+    /// Gives an id to the entries that have none yet.
+    fn assign_ids(&mut self) {
+        for message in self
+            .messages
+            .iter_mut()
+            .filter(|message| message.id.is_empty())
+        {
+            message.id = new_id();
+        }
+        for file in self.files.iter_mut().filter(|file| file.id.is_empty()) {
+            file.id = new_id();
+        }
+    }
+
+    /// Opens the mailbox, changes it with `f` and saves it, while no one else can change it.
+    /// Every change to the mailbox must go through here, so ravens arriving together don't overwrite each other.
+    pub fn update<T>(config: &Config, f: impl FnOnce(&mut Self) -> T) -> Result<T> {
         util::ensure_folder(&config.raven_home)?;
-        std::fs::write(format!("{}/mailbox.toml", config.raven_home), content).context(format!(
+
+        util::locked(&format!("{}/mailbox.lock", config.raven_home), || {
+            let mut mailbox = Self::read(config).context("Opening the mailbox")?;
+            mailbox.assign_ids();
+            let result = f(&mut mailbox);
+            mailbox.save(config)?;
+
+            Ok(result)
+        })
+    }
+
+    fn save(&self, config: &Config) -> Result<()> {
+        let content = toml::to_string(self).context("Serializing the mailbox before saving")?;
+        util::write_atomic(
+            &format!("{}/mailbox.toml", config.raven_home),
+            content.as_bytes(),
+        )
+        .context(format!(
             "Saving the mailbox to {}/mailbox.toml",
             config.raven_home
         ))?;
@@ -65,100 +236,639 @@ impl MailBox {
     }
 
     /// Adds a new message to the mailbox.
-    pub fn add_message(&mut self, from: String, when: DateTime<Utc>, text: String) {
+    pub fn add_message(
+        &mut self,
+        from: String,
+        address: String,
+        when: DateTime<Utc>,
+        text: String,
+    ) {
+        let when = util::chrono_to_toml_datetime(when);
+
+        self.messages.push(MailMessage {
+            id: new_id(),
+            from,
+            address,
+            when,
+            text,
+            read: false,
+            mime: None,
+        });
+    }
+
+    /// Adds a new message from the clipboard of the sender, with the MIME type of its content.
+    pub fn add_clipboard(
+        &mut self,
+        from: String,
+        address: String,
+        when: DateTime<Utc>,
+        text: String,
+        mime: String,
+    ) {
+        let when = util::chrono_to_toml_datetime(when);
+
+        self.messages.push(MailMessage {
+            id: new_id(),
+            from,
+            address,
+            when,
+            text,
+            read: false,
+            mime: Some(mime),
+        });
+    }
+
+    /// Adds a new file to the mailbox, with the id of the `batch` it arrived with if any and the `hash` of its content.
+    pub fn add_file(
+        &mut self,
+        from: String,
+        address: String,
+        when: DateTime<Utc>,
+        name: String,
+        batch: Option<String>,
+        hash: Option<String>,
+    ) {
         let when = util::chrono_to_toml_datetime(when);
 
-        self.messages.push(MailMessage { from, when, text });
+        self.files.push(MailFile {
+            id: new_id(),
+            from,
+            address,
+            when,
+            name,
+            read: false,
+            batch,
+            hash,
+        });
     }
 
-    /// Adds a new file to the mailbox.
-    pub fn add_file(&mut self, from: String, when: DateTime<Utc>, name: String) {
+    /// Adds a transfer that is in progress to the mailbox, replacing any older transfer with the same `id`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_partial(
+        &mut self,
+        id: String,
+        hash: String,
+        from: String,
+        address: String,
+        when: DateTime<Utc>,
+        name: String,
+        size: u64,
+        path: String,
+    ) {
         let when = util::chrono_to_toml_datetime(when);
 
-        self.files.push(MailFile { from, when, name });
+        self.partials.retain(|partial| partial.id != id);
+        self.partials.push(MailPartial {
+            id,
+            hash,
+            from,
+            address,
+            when,
+            name,
+            size,
+            path,
+        });
+    }
+
+    /// Whether there is an interrupted transfer with the given `id` and content `hash`.
+    pub fn has_partial(&self, id: &str, hash: &str) -> bool {
+        self.partials
+            .iter()
+            .any(|partial| partial.id == id && partial.hash == hash)
+    }
+
+    /// Removes a transfer from the mailbox once it finished.
+    pub fn remove_partial(&mut self, id: &str) {
+        self.partials.retain(|partial| partial.id != id);
+    }
+
+    /// Logs a refused raven in the mailbox.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_rejection(
+        &mut self,
+        from: String,
+        address: String,
+        when: DateTime<Utc>,
+        kind: Kind,
+        name: Option<String>,
+        size: u64,
+        reason: String,
+    ) {
+        let when = util::chrono_to_toml_datetime(when);
+
+        self.rejections.push(MailRejection {
+            from,
+            address,
+            when,
+            kind,
+            name,
+            size,
+            reason,
+        });
+    }
+
+    /// Finds the entry whose id is or starts with `id`, among the messages and/or the files.
+    fn find(&self, id: &str, messages: bool, files: bool) -> Result<Entry> {
+        if id.len() < MIN_ID_PREFIX {
+            bail!(
+                "`{}` is too short, use at least {} characters of the id",
+                id,
+                MIN_ID_PREFIX
+            );
+        }
+
+        let mut found = Vec::new();
+        if messages {
+            found.extend(
+                self.messages
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, message)| message.id.starts_with(id))
+                    .map(|(i, message)| (Entry::Message(i), &message.id)),
+            );
+        }
+        if files {
+            found.extend(
+                self.files
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, file)| file.id.starts_with(id))
+                    .map(|(i, file)| (Entry::File(i), &file.id)),
+            );
+        }
+
+        match &found[..] {
+            [] => bail!("No entry with id `{}` in the mailbox", id),
+            [(entry, _)] => Ok(*entry),
+            _ => bail!(
+                "`{}` is ambiguous, it matches {}",
+                id,
+                found
+                    .iter()
+                    .map(|(_, id)| id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    fn mark_read(&mut self, entry: Entry) {
+        match entry {
+            Entry::Message(i) => self.messages[i].read = true,
+            Entry::File(i) => self.files[i].read = true,
+        }
     }
 
-    /// Removes a message from the mailbox.
-    pub fn remove_message(&mut self, index: usize) {
-        if index >= self.messages.len() {
-            println!("Message `{}` not found", index);
-            return;
+    /// Deletes an entry, doing with its received file what `disposal` says.
+    ///
+    /// Nothing outside the data folder is ever touched, whatever the path in the mailbox is,
+    /// and a file modified since it arrived is only removed if forced to.
+    fn remove(&mut self, config: &Config, entry: Entry, disposal: Disposal) -> Result<()> {
+        let i = match entry {
+            Entry::Message(i) => {
+                self.messages.remove(i);
+                return Ok(());
+            }
+            Entry::File(i) => i,
+        };
+
+        let file = &self.files[i];
+        let path = std::path::Path::new(&file.name);
+        match disposal {
+            Disposal::Keep => (),
+            _ if path.symlink_metadata().is_err() => {
+                eprintln!(
+                    "{} isn't there anymore, only the entry is deleted",
+                    file.name
+                )
+            }
+            Disposal::Trash => {
+                file.ensure_received(config)?;
+
+                let trash = format!("{}/{}", config.raven_home, TRASH_FOLDER);
+                util::ensure_folder(&trash).context("Creating the trash folder")?;
+                let trashed = format!("{}/{}", trash, file.id);
+                std::fs::rename(&file.name, &trashed)
+                    .context(format!("Moving {} to the trash", file.name))?;
+
+                eprintln!(
+                    "Moved {} to the trash, `rv mailbox restore {}` puts it back",
+                    file.name,
+                    short(&file.id)
+                );
+                self.trash.push(MailTrashed {
+                    path: trashed,
+                    file: file.clone(),
+                });
+            }
+            Disposal::Delete { force } => {
+                file.ensure_received(config)?;
+
+                match file.check().integrity {
+                    Integrity::Modified if !force => bail!(
+                        "{} was modified since it arrived, use --force to delete it anyway or --keep to keep it",
+                        file.name
+                    ),
+                    Integrity::Unreadable if !force => bail!(
+                        "{} can't be read to check it's what arrived, use --force to delete it anyway or --keep to keep it",
+                        file.name
+                    ),
+                    _ => (),
+                }
+
+                let removed = if path.is_dir() {
+                    std::fs::remove_dir_all(path)
+                } else {
+                    std::fs::remove_file(path)
+                };
+                removed.context(format!("Removing {}", file.name))?;
+            }
         }
-        self.messages.remove(index);
+
+        self.files.remove(i);
+        Ok(())
     }
 
-    /// Removes a file from the mailbox.
-    pub fn remove_file(&mut self, index: usize) {
-        if index >= self.files.len() {
-            println!("File `{}` not found", index);
-            return;
+    /// Finds a file in the trash by its id, or a unique prefix of it.
+    fn find_trashed(&self, id: &str) -> Result<usize> {
+        if id.len() < MIN_ID_PREFIX {
+            bail!(
+                "`{}` is too short, use at least {} characters of the id",
+                id,
+                MIN_ID_PREFIX
+            );
+        }
+
+        let found = self
+            .trash
+            .iter()
+            .enumerate()
+            .filter(|(_, trashed)| trashed.file.id.starts_with(id))
+            .collect::<Vec<_>>();
+
+        match &found[..] {
+            [] => bail!("No file with id `{}` in the trash", id),
+            [(i, _)] => Ok(*i),
+            _ => bail!(
+                "`{}` is ambiguous, it matches {}",
+                id,
+                found
+                    .iter()
+                    .map(|(_, trashed)| trashed.file.id.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
         }
-        let file = self.files.remove(index);
-        let _ =
-            std::fs::remove_file(&file.name).map_err(|e| println!("Failed to remove file: {}", e));
     }
 
-    pub fn list(&self, mut messages: bool, mut files: bool) {
+    /// Puts a file of the trash back in the data folder and in the mailbox, returning its entry.
+    /// It gets a new name if another file took its place meanwhile.
+    fn restore(&mut self, config: &Config, i: usize) -> Result<FileRecord> {
+        let trashed = &self.trash[i];
+        let mut file = trashed.file.clone();
+        file.name = util::non_colliding_filename(&file.name);
+        util::ensure_inside(&format!("{}/data", config.raven_home), &file.name).context(
+            format!("{} can't be restored outside of the data folder", file.name),
+        )?;
+        std::fs::rename(&trashed.path, &file.name)
+            .context(format!("Restoring {} from the trash", file.name))?;
+
+        self.trash.remove(i);
+        let record = file.record();
+        self.files.push(file);
+        Ok(record)
+    }
+
+    /// Removes a file of the trash for good.
+    fn purge(&mut self, config: &Config, i: usize) -> Result<()> {
+        let trashed = &self.trash[i];
+        let path = std::path::Path::new(&trashed.path);
+        if path.symlink_metadata().is_ok() {
+            util::ensure_inside(
+                &format!("{}/{}", config.raven_home, TRASH_FOLDER),
+                &trashed.path,
+            )
+            .context(format!(
+                "{} isn't in the trash, it's left untouched",
+                trashed.path
+            ))?;
+
+            let removed = if path.is_dir() {
+                std::fs::remove_dir_all(path)
+            } else {
+                std::fs::remove_file(path)
+            };
+            removed.context(format!("Removing {} from the trash", trashed.path))?;
+        }
+
+        self.trash.remove(i);
+        Ok(())
+    }
+
+    /// The entries accepted by `filter`, among the messages and/or the files, as listed by `list`.
+    pub fn listing(&self, mut messages: bool, mut files: bool, filter: &Filter) -> MailboxRecord {
         if !messages && !files {
             messages = true;
             files = true;
         }
 
+        let mut listing = MailboxRecord::default();
         if messages {
-            self.list_messages();
+            listing.messages = filter
+                .apply(&self.messages)
+                .iter()
+                .map(|m| m.record())
+                .collect();
         }
-
         if files {
-            self.list_files();
+            listing.files = filter
+                .apply(&self.files)
+                .iter()
+                .map(|f| f.record())
+                .collect();
+            listing.partials = filter
+                .apply(&self.partials)
+                .iter()
+                .map(|p| p.record())
+                .collect();
+            listing.trash = filter
+                .apply(&self.trash)
+                .iter()
+                .map(|t| t.file.record())
+                .collect();
+        }
+        if messages && files {
+            listing.rejections = filter
+                .apply(&self.rejections)
+                .iter()
+                .map(|r| r.record())
+                .collect();
         }
+
+        listing
     }
 
-    fn list_messages(&self) {
-        println!("Messages:");
-        for (i, message) in self.messages.iter().enumerate() {
-            println!("{}: {}", i, message.summary());
+    /// Lists the entries accepted by `filter`, among the messages and/or the files.
+    pub fn list(&self, peers: &PeerNames, mut messages: bool, mut files: bool, filter: &Filter) {
+        if !messages && !files {
+            messages = true;
+            files = true;
+        }
+
+        if messages {
+            println!("Messages:");
+            for message in filter.apply(&self.messages) {
+                print_entry(&message.id, &message.summary(peers), message.read);
+            }
         }
-    }
 
-    fn list_files(&self) {
-        println!("Files:");
-        for (i, file) in self.files.iter().enumerate() {
-            println!("{}: {}", i, file.summary());
+        if files {
+            println!("Files:");
+            for file in filter.apply(&self.files) {
+                print_entry(&file.id, &file.summary(peers), file.read);
+            }
+
+            let partials = filter.apply(&self.partials);
+            if !partials.is_empty() {
+                println!("Partial:");
+                for partial in partials {
+                    print_entry(&partial.id, &partial.summary(peers), true);
+                }
+            }
+
+            let trash = filter.apply(&self.trash);
+            if !trash.is_empty() {
+                println!("Trash:");
+                for trashed in trash {
+                    print_entry(&trashed.file.id, &trashed.file.summary(peers), true);
+                }
+            }
+        }
+
+        if messages && files {
+            let rejections = filter.apply(&self.rejections);
+            if !rejections.is_empty() {
+                println!("Rejected:");
+                for rejection in rejections {
+                    println!("{}", rejection.summary(peers));
+                }
+            }
         }
     }
 
-    pub fn show_message(&self, index: usize) {
-        if let Some(message) = self.messages.get(index) {
-            println!("Message from: {}", message.from);
+    /// Lists the messages whose text matches `filter`, showing where it matches.
+    pub fn search(&self, filter: &Filter) {
+        for message in filter.apply(&self.messages) {
+            let found = filter
+                .matching
+                .as_ref()
+                .and_then(|matching| matching.find(&message.text))
+                .map(|found| found.range())
+                .unwrap_or(0..0);
+
             println!(
-                "When: {}",
-                util::fmt_datetime(util::toml_to_chrono_datetime(message.when))
+                "{}: [{}] From: {} :: {}",
+                short(&message.id),
+                util::fmt_datetime(util::toml_to_chrono_datetime(message.when)),
+                message.from,
+                snippet(&message.text, found)
             );
-            println!("{}", message.text);
-        } else {
-            println!("Message `{}` not found", index);
         }
     }
 
-    pub fn show_file(&self, index: usize) {
-        if let Some(file) = self.files.get(index) {
-            println!("File from: {}", file.from);
-            println!(
-                "When: {}",
-                util::fmt_datetime(util::toml_to_chrono_datetime(file.when))
-            );
-            println!("File: {}", file.name);
-        } else {
-            println!("File `{}` not found", index);
+    /// Checks the received files, or only the one found by `id`, against the hashes stored when they arrived.
+    fn verify(&self, id: Option<&str>) -> Result<VerifyRecord> {
+        let files = match id.map(|id| self.find(id, false, true)).transpose()? {
+            Some(Entry::File(i)) => vec![&self.files[i]],
+            Some(Entry::Message(_)) => unreachable!("only files are looked for"),
+            None => self.files.iter().collect(),
+        };
+
+        Ok(VerifyRecord {
+            files: files.into_iter().map(|file| file.check()).collect(),
+        })
+    }
+
+    /// The entry found by `find`, as shown by `show`.
+    fn entry(&self, entry: Entry) -> EntryRecord {
+        match entry {
+            Entry::Message(i) => EntryRecord::Message(self.messages[i].record()),
+            Entry::File(i) => EntryRecord::File(self.files[i].record()),
+        }
+    }
+
+    /// Writes the text of a message or the content of a file to stdout, as it is.
+    fn show_raw(&self, entry: Entry) -> Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        let written = match entry {
+            Entry::Message(i) => stdout.write_all(self.messages[i].text.as_bytes()),
+            Entry::File(i) => {
+                let file = &self.files[i];
+                let mut f = std::fs::File::open(&file.name)
+                    .context(format!("Opening the received file {}", &file.name))?;
+                std::io::copy(&mut f, &mut stdout).map(|_| ())
+            }
+        };
+
+        match written.and_then(|_| stdout.flush()) {
+            // Whatever reads stdout may stop early, like `head`
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+            written => written.context("Writing to stdout"),
+        }
+    }
+
+    fn show(&self, peers: &PeerNames, entry: Entry) {
+        match entry {
+            Entry::Message(i) => {
+                let message = &self.messages[i];
+                println!("Message: {}", message.id);
+                println!("From: {}", sender(peers, &message.from, &message.address));
+                println!(
+                    "When: {}",
+                    util::fmt_datetime(util::toml_to_chrono_datetime(message.when))
+                );
+                if let Some(mime) = &message.mime {
+                    println!("Clipboard: {}", mime);
+                }
+                println!("{}", message.text);
+            }
+            Entry::File(i) => {
+                let file = &self.files[i];
+                println!("File: {}", file.id);
+                println!("From: {}", sender(peers, &file.from, &file.address));
+                println!(
+                    "When: {}",
+                    util::fmt_datetime(util::toml_to_chrono_datetime(file.when))
+                );
+                if let Some(batch) = &file.batch {
+                    println!("Batch: {}", batch);
+                }
+                if let Some(hash) = &file.hash {
+                    println!("Hash: {}", hash);
+                }
+                println!("Path: {}", file.name);
+            }
+        }
+    }
+}
+
+impl Default for MailBox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MailMessage {
+    fn record(&self) -> MessageRecord {
+        MessageRecord {
+            id: self.id.clone(),
+            from: self.from.clone(),
+            address: self.address.clone(),
+            when: util::fmt_rfc3339(self.when),
+            read: self.read,
+            text: self.text.clone(),
+            mime: self.mime.clone(),
+        }
+    }
+}
+
+impl MailFile {
+    fn record(&self) -> FileRecord {
+        FileRecord {
+            id: self.id.clone(),
+            from: self.from.clone(),
+            address: self.address.clone(),
+            when: util::fmt_rfc3339(self.when),
+            read: self.read,
+            path: self.name.clone(),
+            batch: self.batch.clone(),
+            hash: self.hash.clone(),
+        }
+    }
+
+    /// Makes sure the file is one that arrived in the data folder, before anything is done to it on disk.
+    fn ensure_received(&self, config: &Config) -> Result<()> {
+        util::ensure_inside(&format!("{}/data", config.raven_home), &self.name).context(format!(
+            "{} isn't a file received in the data folder, it's left untouched",
+            self.name
+        ))
+    }
+
+    /// Hashes the file, or the directory, on disk again and compares it to the hash stored when it arrived.
+    fn check(&self) -> CheckRecord {
+        let path = std::path::Path::new(&self.name);
+        let integrity = match &self.hash {
+            _ if path.symlink_metadata().is_err() => Integrity::Missing,
+            None => Integrity::Unknown,
+            Some(hash) => {
+                let current = if path.is_dir() {
+                    directory::digest(&self.name)
+                } else {
+                    util::hash_file(&self.name)
+                };
+
+                match current {
+                    Ok(current) if current == *hash => Integrity::Intact,
+                    Ok(_) => Integrity::Modified,
+                    Err(_) => Integrity::Unreadable,
+                }
+            }
+        };
+
+        CheckRecord {
+            id: self.id.clone(),
+            path: self.name.clone(),
+            hash: self.hash.clone(),
+            integrity,
+        }
+    }
+}
+
+impl MailPartial {
+    /// How many bytes of the file arrived so far.
+    fn received(&self) -> u64 {
+        std::fs::metadata(&self.path)
+            .map(|meta| meta.len())
+            .unwrap_or(0)
+    }
+
+    fn record(&self) -> PartialRecord {
+        PartialRecord {
+            id: self.id.clone(),
+            from: self.from.clone(),
+            address: self.address.clone(),
+            when: util::fmt_rfc3339(self.when),
+            name: self.name.clone(),
+            size: self.size,
+            received: self.received(),
+        }
+    }
+}
+
+impl MailRejection {
+    fn record(&self) -> RejectionRecord {
+        RejectionRecord {
+            from: self.from.clone(),
+            address: self.address.clone(),
+            when: util::fmt_rfc3339(self.when),
+            kind: self.kind,
+            name: self.name.clone(),
+            size: self.size,
+            reason: self.reason.clone(),
         }
     }
 }
 
 impl Summarizable for MailMessage {
-    fn summary(&self) -> String {
+    fn summary(&self, peers: &PeerNames) -> String {
         const SUMMARY_LEN: usize = 32;
 
-        let summary = self.text.chars().take(SUMMARY_LEN).collect::<String>();
+        let summary = self
+            .text
+            .chars()
+            .take(SUMMARY_LEN)
+            .map(|c| if c == '\n' { ' ' } else { c })
+            .collect::<String>();
         let dots = if self.text.len() > SUMMARY_LEN {
             "..."
         } else {
@@ -168,7 +878,7 @@ impl Summarizable for MailMessage {
         format!(
             "[{}] From: {} :: {}{}",
             util::fmt_datetime(util::toml_to_chrono_datetime(self.when)),
-            self.from,
+            sender(peers, &self.from, &self.address),
             summary,
             dots
         )
@@ -176,58 +886,625 @@ impl Summarizable for MailMessage {
 }
 
 impl Summarizable for MailFile {
-    fn summary(&self) -> String {
+    fn summary(&self, peers: &PeerNames) -> String {
+        format!(
+            "[{}] From: {} :: {}{}",
+            util::fmt_datetime(util::toml_to_chrono_datetime(self.when)),
+            sender(peers, &self.from, &self.address),
+            self.name,
+            self.batch
+                .as_ref()
+                .map(|batch| format!(" (batch {})", short(batch)))
+                .unwrap_or_default()
+        )
+    }
+}
+
+impl Summarizable for MailPartial {
+    fn summary(&self, peers: &PeerNames) -> String {
         format!(
-            "[{}] From: {} :: {}",
+            "[{}] From: {} :: {} (partial, {}/{} bytes)",
             util::fmt_datetime(util::toml_to_chrono_datetime(self.when)),
-            self.from,
+            sender(peers, &self.from, &self.address),
+            self.name,
+            self.received(),
+            self.size
+        )
+    }
+}
+
+impl Summarizable for MailRejection {
+    fn summary(&self, peers: &PeerNames) -> String {
+        format!(
+            "[{}] From: {} :: {}{} of {} bytes, {}",
+            util::fmt_datetime(util::toml_to_chrono_datetime(self.when)),
+            sender(peers, &self.from, &self.address),
+            self.kind,
             self.name
+                .as_ref()
+                .map(|name| format!(" {}", name))
+                .unwrap_or_default(),
+            self.size,
+            self.reason
         )
     }
 }
 
-pub fn manage(command: MailboxSubcommands, config: Config) -> Result<()> {
-    let mut mailbox = MailBox::open(&config)?;
+/// Prints an entry when listing, the unread ones are marked with a `*` and highlighted on a terminal.
+fn print_entry(id: &str, summary: &str, read: bool) {
+    if read {
+        println!("  {}: {}", short(id), summary);
+    } else if std::io::stdout().is_terminal() {
+        println!("\x1b[1m* {}: {}\x1b[0m", short(id), summary);
+    } else {
+        println!("* {}: {}", short(id), summary);
+    }
+}
+
+/// Prints the entries that arrive to the mailbox until interrupted.
+fn watch(config: &Config, output: Output) -> Result<()> {
+    const POLL: Duration = Duration::from_millis(500);
+
+    let path = format!("{}/mailbox.toml", config.raven_home);
+    let modified = || {
+        std::fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+
+    let peers = config.peer_names();
+    let mailbox = MailBox::open(config)?;
+    let mut seen = mailbox
+        .messages
+        .iter()
+        .map(|message| message.id.clone())
+        .chain(mailbox.files.iter().map(|file| file.id.clone()))
+        .collect::<HashSet<_>>();
+    let mut rejected = mailbox.rejections.len();
+    let mut last = modified();
+
+    if output == Output::Text {
+        println!("Watching the mailbox, press ctrl-c to stop");
+    }
+    loop {
+        std::thread::sleep(POLL);
+        if modified() == last {
+            continue;
+        }
+        last = modified();
+
+        let mailbox = MailBox::open(config)?;
+        for message in mailbox.messages.iter() {
+            if seen.insert(message.id.clone()) {
+                output.stream(&EntryRecord::Message(message.record()), || {
+                    print_entry(&message.id, &message.summary(&peers), message.read)
+                })?;
+            }
+        }
+        for file in mailbox.files.iter() {
+            if seen.insert(file.id.clone()) {
+                output.stream(&EntryRecord::File(file.record()), || {
+                    print_entry(&file.id, &file.summary(&peers), file.read)
+                })?;
+            }
+        }
+        for rejection in mailbox.rejections.iter().skip(rejected) {
+            output.stream(&EntryRecord::Rejection(rejection.record()), || {
+                println!("  Rejected: {}", rejection.summary(&peers))
+            })?;
+        }
+        rejected = mailbox.rejections.len();
+    }
+}
+
+/// The part of `text` around `found`, in a single line.
+fn snippet(text: &str, found: std::ops::Range<usize>) -> String {
+    const CONTEXT: usize = 24;
+
+    let before = text[..found.start].chars().rev().take(CONTEXT).count();
+    let start = text[..found.start]
+        .char_indices()
+        .rev()
+        .nth(before.saturating_sub(1))
+        .map(|(i, _)| i)
+        .unwrap_or(found.start);
+    let end = text[found.end..]
+        .char_indices()
+        .nth(CONTEXT)
+        .map(|(i, _)| found.end + i)
+        .unwrap_or(text.len());
+
+    format!(
+        "{}{}{}",
+        if start > 0 { "..." } else { "" },
+        text[start..end].replace('\n', " "),
+        if end < text.len() { "..." } else { "" }
+    )
+}
+
+/// A new random id for an entry of the mailbox.
+fn new_id() -> String {
+    let mut id = [0u8; ID_LEN];
+    OsRng.fill_bytes(&mut id);
+
+    hex::encode(id)
+}
+
+/// The beginning of an id shown when listing, usually enough to tell the entries apart.
+fn short(id: &str) -> &str {
+    &id[..id.len().min(SHORT_ID_LEN)]
+}
+
+/// Formats the sender of a raven, with the name of the peer of the address book at its address in place of it.
+/// Entries from older versions have no address.
+fn sender(peers: &PeerNames, from: &str, address: &str) -> String {
+    if address.is_empty() {
+        from.to_string()
+    } else {
+        format!("{} ({})", from, peers.at(address).unwrap_or(address))
+    }
+}
+
+pub fn manage(command: MailboxSubcommands, config: Config, output: Output) -> Result<()> {
+    let mailbox = MailBox::open(&config)?;
 
     match command {
-        MailboxSubcommands::List { files, messages } => mailbox.list(files, messages),
+        MailboxSubcommands::List {
+            files,
+            messages,
+            from,
+            since,
+            until,
+            unread,
+            matching,
+            regex,
+            sort,
+            limit,
+        } => {
+            let mut filter = Filter {
+                from,
+                since: since
+                    .map(|since| util::parse_datetime(&since, false))
+                    .transpose()?,
+                until: until
+                    .map(|until| util::parse_datetime(&until, true))
+                    .transpose()?,
+                unread,
+                matching: None,
+                sort,
+                limit,
+            };
+            if let Some(matching) = matching {
+                filter = filter.matching(&matching, regex)?;
+            }
+
+            output.print(&mailbox.listing(messages, files, &filter), || {
+                mailbox.list(&config.peer_names(), messages, files, &filter)
+            })?;
+        }
+        MailboxSubcommands::Watch => watch(&config, output)?,
+        MailboxSubcommands::Search {
+            query,
+            regex,
+            sort,
+            limit,
+        } => {
+            let filter = Filter {
+                sort,
+                limit,
+                ..Default::default()
+            }
+            .matching(&query, regex)?;
+
+            output.print(&mailbox.listing(true, false, &filter), || {
+                mailbox.search(&filter)
+            })?;
+        }
         MailboxSubcommands::Delete {
-            index,
+            id,
             file,
             message,
+            keep,
+            trash,
+            force,
         } => {
             if file && message {
                 bail!("You can't delete a file and a message at the same time");
             }
 
-            if file {
-                mailbox.remove_file(index);
-            } else if message {
-                mailbox.remove_message(index);
+            let disposal = if keep {
+                Disposal::Keep
+            } else if trash {
+                Disposal::Trash
             } else {
-                bail!("You must specify if you want to delete a file or a message");
-            }
+                Disposal::Delete { force }
+            };
 
-            mailbox.save(&config)?;
+            // Found again holding the mailbox, in case it changed since it was opened
+            MailBox::update(&config, |mailbox| {
+                match mailbox.find(&id, !file, !message) {
+                    Ok(entry) => mailbox.remove(&config, entry, disposal),
+                    // Deleting a file in the trash removes it for good
+                    Err(e) => match mailbox.find_trashed(&id) {
+                        Ok(i) if !message => mailbox.purge(&config, i),
+                        _ => Err(e),
+                    },
+                }
+            })??;
+        }
+        MailboxSubcommands::Restore { id } => {
+            let restored = MailBox::update(&config, |mailbox| {
+                let i = mailbox.find_trashed(&id)?;
+                mailbox.restore(&config, i)
+            })??;
+
+            output.print(&EntryRecord::File(restored.clone()), || {
+                println!("Restored {}", restored.path)
+            })?;
+        }
+        MailboxSubcommands::Verify { id } => {
+            let verified = mailbox.verify(id.as_deref())?;
+            output.print(&verified, || {
+                for file in &verified.files {
+                    println!("{}: {}: {}", short(&file.id), file.path, file.integrity);
+                }
+            })?;
+
+            let failed = verified
+                .files
+                .iter()
+                .filter(|file| !matches!(file.integrity, Integrity::Intact | Integrity::Unknown))
+                .count();
+            if failed > 0 {
+                bail!(
+                    "{} of {} files aren't what arrived",
+                    failed,
+                    verified.files.len()
+                );
+            }
         }
         MailboxSubcommands::Show {
-            index,
+            id,
             file,
             message,
+            raw,
         } => {
             if file && message {
                 bail!("You can't show a file and a message at the same time");
             }
 
-            if file {
-                mailbox.show_file(index);
-            } else if message {
-                mailbox.show_message(index);
+            let entry = mailbox.find(&id, !file, !message)?;
+            if raw {
+                mailbox.show_raw(entry)?;
             } else {
-                bail!("You must specify if you want to see a file or a message");
+                output.print(&mailbox.entry(entry), || {
+                    mailbox.show(&config.peer_names(), entry)
+                })?;
             }
+
+            // Found again holding the mailbox, in case it changed since it was opened
+            MailBox::update(&config, |mailbox| {
+                mailbox
+                    .find(&id, !file, !message)
+                    .map(|entry| mailbox.mark_read(entry))
+            })??;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn config(name: &str) -> Config {
+        let mut config = Config::new();
+        config.raven_home = format!(
+            "{}/raven-test-{}-{}",
+            std::env::temp_dir().to_str().unwrap(),
+            name,
+            std::process::id()
+        );
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+
+        config
+    }
+
+    #[test]
+    fn test_concurrent_updates() {
+        let config = config("mailbox-concurrent");
+
+        let handles = (0..8)
+            .map(|thread| {
+                let config = config.clone();
+                thread::spawn(move || {
+                    for i in 0..25 {
+                        MailBox::update(&config, |mailbox| {
+                            mailbox.add_message(
+                                format!("thread {}", thread),
+                                "127.0.0.1:12345".into(),
+                                Utc::now(),
+                                format!("message {}", i),
+                            )
+                        })
+                        .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let mailbox = MailBox::open(&config).unwrap();
+        assert_eq!(mailbox.messages.len(), 8 * 25);
+
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+    }
+
+    #[test]
+    fn test_ids() {
+        let config = config("mailbox-ids");
+        std::fs::create_dir_all(&config.raven_home).unwrap();
+
+        // A mailbox saved before entries had ids
+        std::fs::write(
+            format!("{}/mailbox.toml", config.raven_home),
+            r#"
+files = []
+
+[[messages]]
+from = "laptop"
+when = 2024-07-01T10:00:00Z
+text = "first"
+
+[[messages]]
+from = "laptop"
+when = 2024-07-01T11:00:00Z
+text = "second"
+"#,
+        )
+        .unwrap();
+
+        let mailbox = MailBox::open(&config).unwrap();
+        let first = mailbox.messages[0].id.clone();
+        assert_eq!(first.len(), ID_LEN * 2);
+        assert_ne!(first, mailbox.messages[1].id);
+
+        // The ids were saved, so they are stable
+        let mailbox = MailBox::open(&config).unwrap();
+        assert_eq!(mailbox.messages[0].id, first);
+
+        assert_eq!(
+            mailbox
+                .find(&first[..MIN_ID_PREFIX + 4], true, true)
+                .unwrap(),
+            Entry::Message(0)
+        );
+        assert!(mailbox
+            .find(&first[..MIN_ID_PREFIX + 4], false, true)
+            .is_err());
+        assert!(mailbox
+            .find(&first[..MIN_ID_PREFIX - 1], true, true)
+            .is_err());
+
+        // Deleting an entry doesn't change the ids of the others
+        let second = mailbox.messages[1].id.clone();
+        MailBox::update(&config, |mailbox| {
+            mailbox.remove(&config, Entry::Message(0), Disposal::Keep)
+        })
+        .unwrap()
+        .unwrap();
+        let mailbox = MailBox::open(&config).unwrap();
+        assert_eq!(
+            mailbox.find(&second, true, true).unwrap(),
+            Entry::Message(0)
+        );
+
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+    }
+
+    #[test]
+    fn test_summaries() {
+        let mut config = config("mailbox-summaries");
+        config.peers.insert(
+            "desktop".into(),
+            crate::config::Peer {
+                address: "10.0.0.7".into(),
+                port: 12345,
+                fingerprint: None,
+                compress: None,
+                encrypt: None,
+            },
+        );
+
+        let mut mailbox = MailBox::new();
+        let when = Utc::now();
+        mailbox.add_message("laptop".into(), "10.0.0.7:4000".into(), when, "hi".into());
+        mailbox.add_message("phone".into(), "10.0.0.8:4000".into(), when, "hi".into());
+        mailbox.add_file(
+            "laptop".into(),
+            "10.0.0.7:4000".into(),
+            when,
+            "a.txt".into(),
+            None,
+            None,
+        );
+
+        let peers = config.peer_names();
+        assert!(mailbox.messages[0]
+            .summary(&peers)
+            .contains("From: laptop (desktop) ::"));
+        assert!(mailbox.messages[1]
+            .summary(&peers)
+            .contains("From: phone (10.0.0.8:4000) ::"));
+        assert!(mailbox.files[0]
+            .summary(&peers)
+            .contains("From: laptop (desktop) ::"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let config = config("mailbox-verify");
+        let data = format!("{}/data", config.raven_home);
+        std::fs::create_dir_all(format!("{}/photos", data)).unwrap();
+        let notes = format!("{}/notes.txt", data);
+        std::fs::write(&notes, "notes").unwrap();
+        std::fs::write(format!("{}/photos/beach.png", data), "beach").unwrap();
+        let photos = format!("{}/photos", data);
+
+        let mut mailbox = MailBox::new();
+        let now = chrono::Utc::now();
+        let hash = util::hash_file(&notes).unwrap();
+        let digest = directory::digest(&photos).unwrap();
+        mailbox.add_file(
+            "laptop".into(),
+            "".into(),
+            now,
+            notes.clone(),
+            None,
+            Some(hash),
+        );
+        mailbox.add_file(
+            "laptop".into(),
+            "".into(),
+            now,
+            photos.clone(),
+            None,
+            Some(digest),
+        );
+        mailbox.add_file("laptop".into(), "".into(), now, notes.clone(), None, None);
+
+        let integrity = |mailbox: &MailBox| {
+            mailbox
+                .verify(None)
+                .unwrap()
+                .files
+                .iter()
+                .map(|file| file.integrity)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            integrity(&mailbox),
+            [Integrity::Intact, Integrity::Intact, Integrity::Unknown]
+        );
+
+        std::fs::write(&notes, "edited notes").unwrap();
+        std::fs::write(format!("{}/photos/extra.png", data), "").unwrap();
+        assert_eq!(
+            integrity(&mailbox),
+            [Integrity::Modified, Integrity::Modified, Integrity::Unknown]
+        );
+
+        std::fs::remove_file(&notes).unwrap();
+        let id = mailbox.files[0].id.clone();
+        let verified = mailbox.verify(Some(&id[..MIN_ID_PREFIX])).unwrap();
+        assert_eq!(verified.files.len(), 1);
+        assert_eq!(verified.files[0].integrity, Integrity::Missing);
+
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+    }
+
+    #[test]
+    fn test_delete() {
+        let config = config("mailbox-delete");
+        let data = format!("{}/data", config.raven_home);
+        std::fs::create_dir_all(&data).unwrap();
+
+        let mut mailbox = MailBox::new();
+        let mut receive = |path: &str, content: &str| {
+            std::fs::write(path, content).unwrap();
+            let hash = util::hash_file(path).unwrap();
+            let now = chrono::Utc::now();
+            mailbox.add_file(
+                "laptop".into(),
+                "".into(),
+                now,
+                path.into(),
+                None,
+                Some(hash),
+            );
+            mailbox.files.len() - 1
+        };
+        let outside = receive(&format!("{}/config.backup", config.raven_home), "keep me");
+        let edited = receive(&format!("{}/edited.txt", data), "as it arrived");
+        let kept = receive(&format!("{}/kept.txt", data), "kept");
+        let trashed = receive(&format!("{}/trashed.txt", data), "trashed");
+        let gone = receive(&format!("{}/gone.txt", data), "gone");
+        std::fs::write(&mailbox.files[edited].name, "edited since").unwrap();
+        std::fs::remove_file(&mailbox.files[gone].name).unwrap();
+
+        let paths = mailbox
+            .files
+            .iter()
+            .map(|file| file.name.clone())
+            .collect::<Vec<_>>();
+        let ids = mailbox
+            .files
+            .iter()
+            .map(|file| file.id.clone())
+            .collect::<Vec<_>>();
+        let delete = |mailbox: &mut MailBox, i: usize, disposal: Disposal| {
+            let entry = mailbox.find(&ids[i], false, true).unwrap();
+            mailbox.remove(&config, entry, disposal)
+        };
+        let force = Disposal::Delete { force: true };
+
+        // Nothing outside the data folder is touched, even forced to
+        assert!(delete(&mut mailbox, outside, force).is_err());
+        assert!(std::path::Path::new(&paths[outside]).exists());
+
+        let err = delete(&mut mailbox, edited, Disposal::Delete { force: false }).unwrap_err();
+        assert!(err.to_string().contains("modified"));
+        assert!(std::path::Path::new(&paths[edited]).exists());
+        delete(&mut mailbox, edited, force).unwrap();
+        assert!(!std::path::Path::new(&paths[edited]).exists());
+
+        delete(&mut mailbox, kept, Disposal::Keep).unwrap();
+        assert!(std::path::Path::new(&paths[kept]).exists());
+
+        delete(&mut mailbox, gone, Disposal::Delete { force: false }).unwrap();
+
+        delete(&mut mailbox, trashed, Disposal::Trash).unwrap();
+        assert!(!std::path::Path::new(&paths[trashed]).exists());
+        assert_eq!(
+            mailbox.listing(false, true, &Filter::default()).trash.len(),
+            1
+        );
+
+        // Restored under a new name, since another file took its place meanwhile
+        std::fs::write(&paths[trashed], "newer").unwrap();
+        let i = mailbox
+            .find_trashed(&ids[trashed][..MIN_ID_PREFIX])
+            .unwrap();
+        let restored = mailbox.restore(&config, i).unwrap();
+        assert_eq!(restored.id, ids[trashed]);
+        assert_eq!(restored.path, format!("{}/trashed_1.txt", data));
+        assert_eq!(std::fs::read_to_string(&restored.path).unwrap(), "trashed");
+        assert!(mailbox.trash.is_empty());
+
+        // Deleting a file in the trash removes it for good
+        delete(&mut mailbox, trashed, Disposal::Trash).unwrap();
+        let i = mailbox.find_trashed(&ids[trashed]).unwrap();
+        mailbox.purge(&config, i).unwrap();
+        assert_eq!(
+            std::fs::read_dir(format!("{}/{}", config.raven_home, TRASH_FOLDER))
+                .unwrap()
+                .count(),
+            0
+        );
+
+        let left = mailbox
+            .files
+            .iter()
+            .map(|file| file.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(left, [ids[outside].as_str()]);
+
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+    }
+}
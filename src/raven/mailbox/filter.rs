@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use clap::ValueEnum;
+use regex::Regex;
+use toml::value::Datetime;
+
+use super::{MailFile, MailMessage, MailPartial, MailRejection, MailTrashed};
+use crate::util;
+
+/// The order in which the entries of the mailbox are listed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Sort {
+    /// The oldest entries first
+    #[default]
+    Oldest,
+    /// The newest entries first
+    Newest,
+    /// By sender, then the oldest entries first
+    Sender,
+}
+
+/// Which entries of the mailbox are listed, in which order and how many of them.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    /// Only the entries whose sender or address contains this, ignoring the case.
+    pub from: Option<String>,
+    /// Only the entries that arrived since this moment (utc).
+    pub since: Option<NaiveDateTime>,
+    /// Only the entries that arrived until this moment (utc).
+    pub until: Option<NaiveDateTime>,
+    /// Only the entries that weren't shown yet.
+    pub unread: bool,
+    /// Only the messages whose text matches, or the files whose name matches.
+    pub matching: Option<Regex>,
+    pub sort: Sort,
+    /// At most this many entries of each kind.
+    pub limit: Option<usize>,
+}
+
+/// The parts of a mailbox entry a `Filter` looks at.
+pub(super) trait Filterable {
+    fn from(&self) -> &str;
+    fn address(&self) -> &str;
+    fn when(&self) -> Datetime;
+    /// The text of a message or the name of a file.
+    fn content(&self) -> &str;
+    /// Whether the entry was shown already, the ones that can't be shown are always read.
+    fn read(&self) -> bool {
+        true
+    }
+}
+
+impl Filter {
+    /// Matches the text of the entries with `pattern`, either a regular expression or a plain text to look for ignoring the case.
+    pub fn matching(mut self, pattern: &str, regex: bool) -> Result<Self> {
+        let pattern = if regex {
+            pattern.to_string()
+        } else {
+            format!("(?i){}", regex::escape(pattern))
+        };
+
+        self.matching = Some(
+            Regex::new(&pattern).context(format!("Invalid regular expression `{}`", pattern))?,
+        );
+        Ok(self)
+    }
+
+    fn accepts(&self, entry: &impl Filterable) -> bool {
+        let when = util::toml_to_chrono_datetime(entry.when());
+
+        self.from.iter().all(|from| {
+            let from = from.to_lowercase();
+            entry.from().to_lowercase().contains(&from) || entry.address().contains(&from)
+        }) && !(self.unread && entry.read())
+            && self.since.iter().all(|since| when >= *since)
+            && self.until.iter().all(|until| when <= *until)
+            && self
+                .matching
+                .iter()
+                .all(|matching| matching.is_match(entry.content()))
+    }
+
+    /// The entries accepted by the filter, sorted and limited.
+    pub(super) fn apply<'a, T: Filterable>(&self, entries: &'a [T]) -> Vec<&'a T> {
+        let mut accepted = entries
+            .iter()
+            .filter(|entry| self.accepts(*entry))
+            .collect::<Vec<_>>();
+
+        let when = |entry: &&T| util::toml_to_chrono_datetime(entry.when());
+        match self.sort {
+            Sort::Oldest => accepted.sort_by_key(when),
+            Sort::Newest => accepted.sort_by_key(|entry| std::cmp::Reverse(when(entry))),
+            Sort::Sender => {
+                accepted.sort_by_key(|entry| (entry.from().to_lowercase(), when(entry)))
+            }
+        }
+
+        if let Some(limit) = self.limit {
+            accepted.truncate(limit);
+        }
+
+        accepted
+    }
+}
+
+impl Filterable for MailMessage {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn when(&self) -> Datetime {
+        self.when
+    }
+
+    fn content(&self) -> &str {
+        &self.text
+    }
+
+    fn read(&self) -> bool {
+        self.read
+    }
+}
+
+impl Filterable for MailFile {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn when(&self) -> Datetime {
+        self.when
+    }
+
+    fn content(&self) -> &str {
+        util::basename(&self.name)
+    }
+
+    fn read(&self) -> bool {
+        self.read
+    }
+}
+
+impl Filterable for MailTrashed {
+    fn from(&self) -> &str {
+        &self.file.from
+    }
+
+    fn address(&self) -> &str {
+        &self.file.address
+    }
+
+    fn when(&self) -> Datetime {
+        self.file.when
+    }
+
+    fn content(&self) -> &str {
+        util::basename(&self.file.name)
+    }
+}
+
+impl Filterable for MailPartial {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn when(&self) -> Datetime {
+        self.when
+    }
+
+    fn content(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Filterable for MailRejection {
+    fn from(&self) -> &str {
+        &self.from
+    }
+
+    fn address(&self) -> &str {
+        &self.address
+    }
+
+    fn when(&self) -> Datetime {
+        self.when
+    }
+
+    fn content(&self) -> &str {
+        self.name.as_deref().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raven::mailbox::MailBox;
+
+    #[test]
+    fn test_filter() {
+        let mut mailbox = MailBox::new();
+        for (from, date, text) in [
+            ("laptop", "2024-07-01 10:00", "the build failed"),
+            ("phone", "2024-07-02 10:00", "buy milk"),
+            ("laptop", "2024-07-03 10:00", "the build passed"),
+        ] {
+            let when = util::parse_datetime(date, false).unwrap().and_utc();
+            mailbox.add_message(from.into(), "10.0.0.5:12345".into(), when, text.into());
+        }
+        mailbox.messages[1].read = true;
+        let texts = |filter: &Filter| {
+            filter
+                .apply(&mailbox.messages)
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        let filter = Filter {
+            from: Some("LAPTOP".into()),
+            sort: Sort::Newest,
+            ..Default::default()
+        };
+        assert_eq!(texts(&filter), ["the build passed", "the build failed"]);
+
+        let filter = Filter {
+            since: Some(util::parse_datetime("2024-07-02", false).unwrap()),
+            until: Some(util::parse_datetime("2024-07-02", true).unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(texts(&filter), ["buy milk"]);
+
+        let filter = Filter::default().matching("BUILD", false).unwrap();
+        assert_eq!(texts(&filter), ["the build failed", "the build passed"]);
+
+        let filter = Filter {
+            limit: Some(1),
+            ..Default::default()
+        }
+        .matching("build (passed|failed)$", true)
+        .unwrap();
+        assert_eq!(texts(&filter), ["the build failed"]);
+        assert!(Filter::default().matching("(", true).is_err());
+
+        let filter = Filter {
+            unread: true,
+            ..Default::default()
+        };
+        assert_eq!(texts(&filter), ["the build failed", "the build passed"]);
+    }
+}
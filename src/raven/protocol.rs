@@ -0,0 +1,462 @@
+use std::io::{Read, Write};
+
+use std::fmt::Display;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    error::ProtocolError,
+    raven::{compression::Compression, crypto::Cipher},
+};
+
+/// The bytes every raven conversation starts with.
+pub const MAGIC: [u8; 4] = *b"RAVN";
+/// The version of the wire protocol spoken by this client.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The biggest frame a peer is allowed to send.
+pub const MAX_FRAME_LEN: u32 = 16 * 1024 * 1024;
+/// The size of the chunks in which streamed content is split.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The type of the message carried after the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Kind {
+    Text = 1,
+    File = 2,
+    Clipboard = 3,
+    Directory = 4,
+    Batch = 5,
+}
+
+/// The header that opens every raven conversation.
+///
+/// On the wire it is the `MAGIC`, the protocol version (big endian `u16`) and the message type (`u8`).
+/// The sender writes its header and the receiver answers with its own, so both ends know which version the other speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    pub kind: Kind,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Kind::Text),
+            2 => Ok(Kind::File),
+            3 => Ok(Kind::Clipboard),
+            4 => Ok(Kind::Directory),
+            5 => Ok(Kind::Batch),
+            _ => Err(ProtocolError::UnknownKind(value)),
+        }
+    }
+}
+
+impl Display for Kind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kind::Text => write!(f, "text"),
+            Kind::File => write!(f, "file"),
+            Kind::Clipboard => write!(f, "clipboard"),
+            Kind::Directory => write!(f, "directory"),
+            Kind::Batch => write!(f, "batch"),
+        }
+    }
+}
+
+impl Header {
+    /// Creates a header for the current protocol version.
+    pub fn new(kind: Kind) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            kind,
+        }
+    }
+
+    /// Writes the header to the stream.
+    pub fn write_to(&self, w: &mut impl Write) -> Result<(), ProtocolError> {
+        let mut buf = [0u8; 7];
+        buf[..4].copy_from_slice(&MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_be_bytes());
+        buf[6] = self.kind as u8;
+
+        w.write_all(&buf)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Reads a header from the stream.
+    /// Fails if the magic bytes don't match, but doesn't check the version, use `Header::check_version` for that.
+    pub fn read_from(r: &mut impl Read) -> Result<Self, ProtocolError> {
+        let mut buf = [0u8; 7];
+        r.read_exact(&mut buf)?;
+
+        let magic = [buf[0], buf[1], buf[2], buf[3]];
+        if magic != MAGIC {
+            return Err(ProtocolError::BadMagic(magic));
+        }
+
+        Ok(Self {
+            version: u16::from_be_bytes([buf[4], buf[5]]),
+            kind: Kind::try_from(buf[6])?,
+        })
+    }
+
+    /// Fails if the header was written by a peer speaking another version of the protocol.
+    pub fn check_version(&self) -> Result<(), ProtocolError> {
+        if self.version != PROTOCOL_VERSION {
+            Err(ProtocolError::UnsupportedVersion {
+                theirs: self.version,
+                ours: PROTOCOL_VERSION,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Writes a length delimited frame to the stream.
+pub fn write_frame(w: &mut impl Write, payload: &[u8]) -> Result<(), ProtocolError> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_LEN)
+        .ok_or(ProtocolError::FrameTooLarge(payload.len() as u32))?;
+
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(payload)?;
+    Ok(())
+}
+
+/// Reads a length delimited frame from the stream.
+pub fn read_frame(r: &mut impl Read) -> Result<Vec<u8>, ProtocolError> {
+    read_frame_max(r, MAX_FRAME_LEN)
+}
+
+/// Reads a length delimited frame from the stream, refusing frames longer than `max` before reading them.
+pub fn read_frame_max(r: &mut impl Read, max: u32) -> Result<Vec<u8>, ProtocolError> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+
+    let len = u32::from_be_bytes(len);
+    if len > max {
+        return Err(ProtocolError::FrameTooLarge(len));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// A conversation with a peer over `stream`, made of frames that may be compressed and encrypted.
+///
+/// The conversation starts in plaintext, once the handshake agrees on the keys `Channel::encrypt` turns encryption on
+/// for every following frame. Likewise `Channel::compress` turns compression on once it's negotiated.
+pub struct Channel<S> {
+    stream: S,
+    cipher: Option<Cipher>,
+    compression: Option<Compression>,
+    max_frame: u32,
+}
+
+impl<S: Read + Write> Channel<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            cipher: None,
+            compression: None,
+            max_frame: MAX_FRAME_LEN,
+        }
+    }
+
+    /// The underlying stream, writing to it directly bypasses the framing and the encryption.
+    pub fn stream(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Encrypts every frame from now on.
+    pub fn encrypt(&mut self, cipher: Cipher) {
+        self.cipher = Some(cipher);
+    }
+
+    /// Compresses every frame from now on, before encrypting it.
+    pub fn compress(&mut self, compression: Compression) {
+        self.compression = Some(compression);
+    }
+
+    /// Refuses frames with more than `max` bytes of content from now on, without reading them.
+    /// The limit can't be raised above `MAX_FRAME_LEN`.
+    pub fn limit_frames(&mut self, max: u64) {
+        let overhead = if self.cipher.is_some() { 16 } else { 0 };
+
+        self.max_frame = max.saturating_add(overhead).min(MAX_FRAME_LEN as u64) as u32;
+    }
+
+    /// Whether the frames are being encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// How the frames are being compressed, if they are.
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    /// Writes a frame, compressing and encrypting it if needed.
+    /// Empty frames are never compressed, so they keep marking the end of a stream.
+    pub fn write_frame(&mut self, payload: &[u8]) -> Result<(), ProtocolError> {
+        let compressed;
+        let payload = match self.compression {
+            Some(compression) if !payload.is_empty() => {
+                compressed = compression.compress(payload)?;
+                &compressed[..]
+            }
+            _ => payload,
+        };
+
+        match &mut self.cipher {
+            Some(cipher) => write_frame(&mut self.stream, &cipher.seal(payload)?),
+            None => write_frame(&mut self.stream, payload),
+        }
+    }
+
+    /// Reads a frame, decrypting and decompressing it if needed.
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        let frame = read_frame_max(&mut self.stream, self.max_frame)?;
+
+        let frame = match &mut self.cipher {
+            Some(cipher) => cipher.open(&frame)?,
+            None => frame,
+        };
+
+        match self.compression {
+            Some(compression) if !frame.is_empty() => {
+                compression.decompress(&frame, self.max_frame as usize)
+            }
+            _ => Ok(frame),
+        }
+    }
+
+    /// Serializes `value` with bincode and writes it as a single frame.
+    pub fn write_message<T: Serialize>(&mut self, value: &T) -> anyhow::Result<()> {
+        let encoded = bincode::serialize(value)?;
+        self.write_frame(&encoded)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    /// Reads a single frame and deserializes it with bincode.
+    pub fn read_message<T: DeserializeOwned>(&mut self) -> anyhow::Result<T> {
+        let frame = self.read_frame()?;
+        Ok(bincode::deserialize(&frame)?)
+    }
+
+    /// Streams everything from `r` as a series of frames of at most `CHUNK_SIZE` bytes.
+    /// The end of the content is marked by an empty frame.
+    ///
+    /// Returns the amount of content bytes written.
+    pub fn write_stream(&mut self, r: &mut impl Read) -> Result<u64, ProtocolError> {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut total = 0;
+
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ProtocolError::Io(e)),
+            };
+
+            self.write_frame(&buf[..n])?;
+            total += n as u64;
+        }
+
+        self.write_frame(&[])?;
+        self.stream.flush()?;
+        Ok(total)
+    }
+
+    /// Reads the frames written by `Channel::write_stream` and writes their content to `w` as they arrive.
+    /// Fails as soon as more than `max` bytes of content arrive.
+    ///
+    /// Returns the amount of content bytes read.
+    pub fn read_stream(&mut self, w: &mut impl Write, max: u64) -> Result<u64, ProtocolError> {
+        let mut total = 0;
+
+        loop {
+            let chunk = self.read_frame()?;
+            if chunk.is_empty() {
+                break;
+            }
+
+            if total + chunk.len() as u64 > max {
+                return Err(ProtocolError::StreamTooLong(max));
+            }
+
+            w.write_all(&chunk)?;
+            total += chunk.len() as u64;
+        }
+
+        w.flush()?;
+        Ok(total)
+    }
+}
+
+/// Hashes with blake3 the content read or written through it, so both ends can tell
+/// the content streamed is the content that arrived.
+pub struct Hashed<T> {
+    inner: T,
+    hasher: blake3::Hasher,
+}
+
+impl<T> Hashed<T> {
+    pub fn new(inner: T) -> Self {
+        Self::resume(inner, blake3::Hasher::new())
+    }
+
+    /// Goes on hashing with a `hasher` that already saw the beginning of the content, of a resumed transfer.
+    pub fn resume(inner: T, hasher: blake3::Hasher) -> Self {
+        Self { inner, hasher }
+    }
+
+    /// The hex encoded hash of the content read or written so far.
+    pub fn hash(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Hashed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<W: Write> Write for Hashed<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        error::ProtocolError,
+        raven::crypto::{Ephemeral, Role},
+    };
+
+    #[test]
+    fn test_header_roundtrip() {
+        let mut buf = Vec::new();
+        Header::new(Kind::File).write_to(&mut buf).unwrap();
+
+        let header = Header::read_from(&mut Cursor::new(buf)).unwrap();
+        assert_eq!(header, Header::new(Kind::File));
+        assert!(header.check_version().is_ok());
+    }
+
+    #[test]
+    fn test_header_rejects_garbage() {
+        let garbage = b"\x00\x01\x02\x03\x00\x01\x01".to_vec();
+        let err = Header::read_from(&mut Cursor::new(garbage)).unwrap_err();
+        assert!(matches!(err, ProtocolError::BadMagic(_)));
+
+        let mut future = Vec::new();
+        Header {
+            version: PROTOCOL_VERSION + 1,
+            kind: Kind::Text,
+        }
+        .write_to(&mut future)
+        .unwrap();
+
+        let header = Header::read_from(&mut Cursor::new(future)).unwrap();
+        assert!(matches!(
+            header.check_version(),
+            Err(ProtocolError::UnsupportedVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"hello").unwrap();
+        write_frame(&mut buf, b"").unwrap();
+
+        let mut r = Cursor::new(buf);
+        assert_eq!(read_frame(&mut r).unwrap(), b"hello");
+        assert_eq!(read_frame(&mut r).unwrap(), b"");
+        assert!(matches!(
+            read_frame(&mut r),
+            Err(ProtocolError::UnexpectedEnd)
+        ));
+
+        let huge = (MAX_FRAME_LEN + 1).to_be_bytes().to_vec();
+        assert!(matches!(
+            read_frame(&mut Cursor::new(huge)),
+            Err(ProtocolError::FrameTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_stream() {
+        let content = (0..CHUNK_SIZE * 3 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+
+        let mut channel = Channel::new(Cursor::new(Vec::new()));
+        let written = channel.write_stream(&mut Cursor::new(&content)).unwrap();
+        assert_eq!(written, content.len() as u64);
+
+        channel.stream().set_position(0);
+        let mut received = Vec::new();
+        let read = channel.read_stream(&mut received, written).unwrap();
+        assert_eq!(read, written);
+        assert_eq!(received, content);
+
+        channel.stream().set_position(0);
+        assert!(matches!(
+            channel.read_stream(&mut Vec::new(), written - 1),
+            Err(ProtocolError::StreamTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_stream() {
+        let sender = Ephemeral::new();
+        let receiver = Ephemeral::new();
+        let (sender_key, receiver_key) = (sender.public_key(), receiver.public_key());
+
+        let mut to = Channel::new(Cursor::new(Vec::new()));
+        to.encrypt(sender.agree(&receiver_key, b"", Role::Sender).unwrap());
+        to.write_message(&"helo".to_string()).unwrap();
+        to.write_stream(&mut Cursor::new(b"secret content"))
+            .unwrap();
+
+        let wire = to.stream().get_ref().clone();
+        assert!(!wire.windows(6).any(|window| window == b"secret"));
+
+        let mut from = Channel::new(Cursor::new(wire));
+        from.encrypt(receiver.agree(&sender_key, b"", Role::Receiver).unwrap());
+        assert_eq!(from.read_message::<String>().unwrap(), "helo");
+
+        let mut received = Vec::new();
+        from.read_stream(&mut received, u64::MAX).unwrap();
+        assert_eq!(received, b"secret content");
+    }
+}
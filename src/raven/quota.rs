@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use toml::value::Datetime;
+
+use crate::{config::Config, util};
+
+/// Keeps track of how much data each sender sent today, so the receiver can enforce the daily limits.
+/// Trusted senders are known by their public key, untrusted ones by their ip address, as they may make up a new key
+/// for every raven.
+///
+/// The quota is stored in `quota.toml` in the raven home folder and resets every day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    day: Datetime,
+    /// The bytes received today, by the public key or the ip address of the sender.
+    used: HashMap<String, u64>,
+}
+
+impl Quota {
+    /// Creates an empty quota for today.
+    pub fn new() -> Self {
+        Self {
+            day: Datetime {
+                date: Some(util::chrono_to_toml_date(chrono::Utc::now().date_naive())),
+                time: None,
+                offset: None,
+            },
+            used: HashMap::new(),
+        }
+    }
+
+    /// Opens the quota of today, the quota of any other day is discarded.
+    pub fn open(config: &Config) -> Result<Self> {
+        let path = format!("{}/quota.toml", config.raven_home);
+        if !std::path::Path::new(&path).exists() {
+            return Ok(Self::new());
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let quota = toml::from_str::<Self>(&content)
+            .context(format!("Failed to deserialize the quota from {}", &path))?;
+
+        if quota.day == Self::new().day {
+            Ok(quota)
+        } else {
+            Ok(Self::new())
+        }
+    }
+
+    /// Opens the quota, changes it with `f` and saves it, while no one else can change it.
+    pub fn update<T>(config: &Config, f: impl FnOnce(&mut Self) -> T) -> Result<T> {
+        util::ensure_folder(&config.raven_home)?;
+
+        util::locked(&format!("{}/quota.lock", config.raven_home), || {
+            let mut quota = Self::open(config).context("Opening the quota")?;
+            let result = f(&mut quota);
+            quota.save(config)?;
+
+            Ok(result)
+        })
+    }
+
+    fn save(&self, config: &Config) -> Result<()> {
+        let content = toml::to_string(self).context("Serializing the quota before saving")?;
+        util::write_atomic(
+            &format!("{}/quota.toml", config.raven_home),
+            content.as_bytes(),
+        )
+        .context(format!(
+            "Saving the quota to {}/quota.toml",
+            config.raven_home
+        ))?;
+
+        Ok(())
+    }
+
+    /// How many bytes the given sender sent today.
+    pub fn used(&self, sender: &str) -> u64 {
+        self.used.get(sender).copied().unwrap_or(0)
+    }
+
+    /// Accounts `bytes` more to the given sender, unless they go over `max`. Returns whether they were accounted.
+    pub fn claim(&mut self, sender: &str, bytes: u64, max: Option<u64>) -> bool {
+        let used = self.used.entry(sender.to_string()).or_insert(0);
+        if max.is_some_and(|max| *used + bytes > max) {
+            return false;
+        }
+        *used += bytes;
+
+        true
+    }
+
+    /// Gives back `bytes` accounted to the given sender that didn't arrive after all.
+    pub fn refund(&mut self, sender: &str, bytes: u64) {
+        if let Some(used) = self.used.get_mut(sender) {
+            *used = used.saturating_sub(bytes);
+        }
+    }
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,70 +1,1295 @@
-use std::{io::Read, net::{TcpListener, TcpStream}, sync::Arc};
+use std::{
+    io::{BufWriter, Read, Seek, SeekFrom},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+};
 
 use anyhow::{bail, Context, Result};
+use socket2::{Domain, Socket, Type};
 
 use crate::{
-    config::Config,
-    raven::{mailbox::MailBox, Raven},
+    clipboard::{self, Clipboard},
+    config::{Config, Limits},
+    error::ProtocolError,
+    raven::{
+        self,
+        compression::Compression,
+        crypto::{Ephemeral, Role},
+        directory::{self, DirEntry},
+        mailbox::MailBox,
+        protocol::{Channel, Hashed, Header, Kind, CHUNK_SIZE, MAX_FRAME_LEN},
+        quota::Quota,
+        BatchFile, Helo, Raven, Rejection, SysRaven,
+    },
+    trust::{self, Identity, TrustStore},
     util,
 };
 
-/// Opens the client for receiving messages from a raven
-/// The receiver works in a loop, listening for incoming connections and printing the received message.
-/// The receiver will listen on the provided ipv4 address(`from`) and `port`.
+/// The bytes a raven frame may take besides the content announced by the `Helo`.
+const RAVEN_OVERHEAD: u64 = 4096;
+/// Compressing content that doesn't shrink grows it by less than a part in this many.
+const COMPRESSION_GROWTH: u64 = 64;
+/// The folder in the raven home where the received content that doesn't match its hash is moved.
+const QUARANTINE_FOLDER: &str = "quarantine";
+
+/// Who sent a raven.
+struct Sender {
+    /// The verified identity of the sender.
+    from: String,
+    /// The address the raven came from.
+    address: String,
+    /// Who the daily quota is kept for: the key of a trusted sender, the ip address of an untrusted one.
+    quota: String,
+}
+
+/// How many connections wait to be accepted before the ones beyond are refused.
+const BACKLOG: i32 = 128;
+
+/// Opens the listener where the ravens arrive, on the ip `address` and `port`.
+/// The unspecified ipv6 address `::` listens on every ipv4 address too, or only on them if the device has no ipv6.
+pub fn listen(address: &str, port: u16) -> Result<TcpListener> {
+    let ip = address
+        .parse::<IpAddr>()
+        .context(format!("Invalid ip address {}", address))?;
+    let dual_stack = ip == IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+    match bind(SocketAddr::new(ip, port), dual_stack) {
+        Err(e) if dual_stack => {
+            println!("Listening on ipv4 only, ipv6 isn't available: {}", e);
+            bind(
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+                false,
+            )
+        }
+        bound => bound,
+    }
+    .context(format!("Listening on {}", util::fmt_address(address, port)))
+}
+
+fn bind(address: SocketAddr, dual_stack: bool) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if dual_stack {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(BACKLOG)?;
+
+    Ok(socket.into())
+}
+
+/// Receives the raven of a single connection, accepted by the daemon from the listener opened by `listen`.
+/// The daemon owns the accept loop and the shutdown, and runs every connection on its own thread.
 ///
-/// This function actually only returns an error if the connection fails to be established. Otherwise it will loop forever.
-pub fn receive(mut stream: TcpStream, config: Arc<Config>) -> Result<()> {
-    let sender = stream
+/// Returns once the raven is stored or refused, or with an error if the connection fails midway.
+/// Every sender must prove it owns the key it announces, the verified key is recorded as the sender in the mailbox.
+/// If the sender asks for it, every frame after the handshake is encrypted.
+pub fn receive(stream: TcpStream, config: Arc<Config>, identity: Arc<Identity>) -> Result<()> {
+    // An ipv4 sender reaching a dual stack listener comes from a mapped ipv6 address, written back as ipv4
+    let peer = stream
         .peer_addr()
+        .map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()));
+    let sender = peer
+        .as_ref()
         .map(|addr| addr.to_string())
         .unwrap_or("".into());
+    let sender_ip = peer
+        .as_ref()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or("".into());
 
     println!("Connection established: {}", &sender);
 
-    let mut buffer = Vec::new();
-    if let Err(e) = stream.read_to_end(&mut buffer).context("Receiving raven") {
-        bail!("Failed to read the message {}", e);
+    let mut channel = Channel::new(stream);
+    let header = Header::read_from(channel.stream()).context("Reading the raven header")?;
+
+    // Always answer with our own header, so the sender knows which version we speak
+    Header::new(header.kind)
+        .write_to(channel.stream())
+        .context("Answering the raven header")?;
+    header.check_version()?;
+
+    let helo = match channel.read_message::<SysRaven>() {
+        Ok(SysRaven::Helo(helo)) => helo,
+        Ok(other) => bail!("Expected a helo but {:?} arrived", other),
+        Err(e) => bail!("Failed to read the helo: {}", e),
+    };
+
+    if helo.kind != header.kind {
+        return Err(ProtocolError::KindMismatch.into());
+    }
+
+    println!(
+        "Helo from {} ({}): {} of {} bytes",
+        helo.from, &sender, helo.kind, helo.size
+    );
+
+    if config.receiver.require_encryption && helo.ephemeral.is_none() {
+        let unverified = Sender {
+            from: format!("{} (unverified)", helo.from),
+            address: sender,
+            quota: sender_ip,
+        };
+        return refuse(
+            &mut channel,
+            &config,
+            &helo,
+            &unverified,
+            Rejection::Unencrypted,
+        );
+    }
+
+    // Challenges the sender to prove it owns the announced key, while proving our own
+    let mut nonce = vec![0u8; 32];
+    rand_core::RngCore::fill_bytes(&mut rand_core::OsRng, &mut nonce);
+    let ephemeral = helo.ephemeral.as_ref().map(|_| Ephemeral::new());
+    let ephemeral_key = ephemeral.as_ref().map(|ephemeral| ephemeral.public_key());
+
+    let transcript = raven::transcript(
+        &helo,
+        &nonce,
+        &identity.public_key(),
+        ephemeral_key.as_deref(),
+    );
+    channel
+        .write_message(&SysRaven::Challenge {
+            nonce,
+            key: identity.public_key(),
+            ephemeral: ephemeral_key,
+            signature: identity.sign(&raven::signed(Role::Receiver, &transcript)),
+        })
+        .context("Challenging the sender")?;
+
+    let signature = match channel.read_message::<SysRaven>() {
+        Ok(SysRaven::Proof { signature }) => signature,
+        Ok(other) => bail!("Expected a proof but {:?} arrived", other),
+        Err(e) => bail!("Failed to read the proof: {}", e),
+    };
+
+    if let Err(e) = trust::verify(
+        &helo.key,
+        &raven::signed(Role::Sender, &transcript),
+        &signature,
+    ) {
+        let _ = channel.write_message(&SysRaven::Reject(Rejection::Untrusted));
+        bail!("The sender failed to prove its identity: {}", e);
+    }
+
+    if let (Some(ephemeral), Some(theirs)) = (ephemeral, &helo.ephemeral) {
+        channel.encrypt(ephemeral.agree(theirs, &transcript, Role::Receiver)?);
     }
 
-    let rv = match bincode::deserialize::<Raven>(&buffer).context("Deserializing received raven") {
+    let trust = TrustStore::open(&config).context("Opening the trust store")?;
+    let trusted = trust.find(&helo.key, &sender_ip);
+    let from = match trusted {
+        Some(peer) => format!("{} [{}]", peer.name, trust::fingerprint(&helo.key)),
+        None => format!(
+            "{} (untrusted) [{}]",
+            helo.from,
+            trust::fingerprint(&helo.key)
+        ),
+    };
+    let sender = Sender {
+        from,
+        address: sender,
+        quota: match trusted {
+            Some(_) => helo.key.clone(),
+            None => sender_ip,
+        },
+    };
+
+    // Trusted peers may override the limits of the receiver
+    let limits = match trusted {
+        Some(peer) => config.receiver.limits.overridden_by(&peer.limits),
+        None => config.receiver.limits.clone(),
+    };
+
+    if let Some(reason) = judge(&config, &helo, trusted.is_some(), &limits) {
+        return refuse(&mut channel, &config, &helo, &sender, reason);
+    }
+
+    // Checked and accounted at once, so two ravens arriving together can't both fit in what's left of the quota
+    let claimed = Quota::update(&config, |quota| {
+        quota.claim(&sender.quota, helo.size, limits.max_daily)
+    })
+    .context("Accounting the raven in the daily quota")?;
+    if let (false, Some(max)) = (claimed, limits.max_daily) {
+        return refuse(
+            &mut channel,
+            &config,
+            &helo,
+            &sender,
+            Rejection::DailyQuota { max },
+        );
+    }
+
+    let compression = Compression::negotiate(&helo.compression);
+    channel
+        .write_message(&SysRaven::Accept { compression })
+        .context("Accepting the raven")?;
+    if let Some(compression) = compression {
+        channel.compress(compression);
+    }
+
+    // From now on refuses anything bigger than what was announced, before even reading it
+    // The size of a directory or a batch doesn't tell how long the list of its entries is
+    let announced = match helo.kind {
+        Kind::Directory | Kind::Batch => MAX_FRAME_LEN as u64,
+        // A message or a clipboard arrives whole in its raven, compressing what doesn't shrink grows it a little
+        Kind::Text | Kind::Clipboard => helo.size + helo.size / COMPRESSION_GROWTH,
+        // The content of a file follows its raven in chunks
+        Kind::File => helo.size.min(CHUNK_SIZE as u64),
+    };
+    channel.limit_frames(announced + RAVEN_OVERHEAD);
+
+    let rv = match channel.read_message::<Raven>() {
         Ok(rv) => rv,
         Err(e) => {
-            bail!("Failed to deserialize the received raven: {}", e);
+            bail!("Failed to read the raven: {}", e);
         }
     };
 
+    if !helo.announces(&rv) {
+        bail!("The raven doesn't match the helo that announced it");
+    }
+
     match rv {
-        Raven::Text { text } => message(&config, sender, text),
-        Raven::File { name, content } => file(&config, sender, name, content)
+        Raven::Text { text } => message(&config, &mut channel, &sender, text),
+        Raven::Directory {
+            name,
+            size,
+            entries,
+        } => self::directory(&config, &mut channel, &sender, name, size, entries),
+        Raven::Clipboard { text, mime } => {
+            let paste = trusted.is_some_and(|peer| peer.clipboard);
+            clipboard(&config, &mut channel, &helo, &sender, text, mime, paste)
+        }
+        Raven::File {
+            id,
+            name,
+            size,
+            hash,
+        } => file(&config, &mut channel, &sender, id, name, size, hash, None),
+        Raven::Batch { id, files } => {
+            batch(&config, &mut channel, &helo, &sender, &limits, id, files)
+        }
+    }
+}
+
+/// Refuses the raven announced by `helo`, logging the rejection in the mailbox.
+fn refuse(
+    channel: &mut Channel<TcpStream>,
+    config: &Config,
+    helo: &Helo,
+    sender: &Sender,
+    reason: Rejection,
+) -> Result<()> {
+    channel
+        .write_message(&SysRaven::Reject(reason.clone()))
+        .context("Refusing the raven")?;
+    println!("Refused raven from {}: {}", &sender.address, reason);
+
+    MailBox::update(config, |mailbox| {
+        mailbox.add_rejection(
+            sender.from.clone(),
+            sender.address.clone(),
+            chrono::Utc::now(),
+            helo.kind,
+            helo.name.clone(),
+            helo.size,
+            reason.to_string(),
+        )
+    })
+}
+
+/// Decides whether the raven announced by `helo` is accepted, returning the reason to refuse it otherwise.
+fn judge(config: &Config, helo: &Helo, trusted: bool, limits: &Limits) -> Option<Rejection> {
+    if config.receiver.trusted_only && !trusted {
+        return Some(Rejection::Untrusted);
+    }
+
+    // A batch is just many files, it's accepted wherever files are
+    let kind = match helo.kind {
+        Kind::Batch => Kind::File,
+        kind => kind,
+    };
+    if !config.receiver.accept.contains(&kind) {
+        return Some(Rejection::KindNotAllowed(kind));
+    }
+
+    // The files of a batch are held to `max_file` one by one, once they're listed
+    let unsafe_name = helo
+        .name
+        .as_ref()
+        .filter(|name| util::safe_filename(name).is_err());
+    if let Some(name) = unsafe_name {
+        return Some(Rejection::UnsafeName(name.clone()));
+    }
+
+    let max_size = match helo.kind {
+        Kind::Text | Kind::Clipboard => limits.max_message,
+        Kind::File | Kind::Directory => limits.max_file,
+        Kind::Batch => None,
+    };
+    if let Some(max) = max_size.filter(|max| helo.size > *max) {
+        return Some(Rejection::TooLarge { max });
+    }
+
+    if matches!(helo.kind, Kind::File | Kind::Directory | Kind::Batch) {
+        let raven_arrivals = format!("{}/data", &config.raven_home);
+        let available = util::ensure_folder(&raven_arrivals)
+            .and_then(|_| Ok(fs2::available_space(&raven_arrivals)?))
+            .unwrap_or(u64::MAX);
+
+        if helo.size > available {
+            return Some(Rejection::DiskFull);
+        }
+
+        if let Some(max) = limits.max_storage {
+            if util::folder_size(&raven_arrivals) + helo.size > max {
+                return Some(Rejection::StorageFull { max });
+            }
+        }
     }
+
+    None
+}
+
+/// Gives back to the daily quota of the sender the bytes of its raven that don't have to travel.
+fn refund(config: &Config, sender: &Sender, bytes: u64) -> Result<()> {
+    if bytes == 0 {
+        return Ok(());
+    }
+
+    Quota::update(config, |quota| quota.refund(&sender.quota, bytes))
+}
+
+fn message(
+    config: &Config,
+    channel: &mut Channel<TcpStream>,
+    sender: &Sender,
+    text: String,
+) -> Result<()> {
+    MailBox::update(config, |mailbox| {
+        mailbox.add_message(
+            sender.from.clone(),
+            sender.address.clone(),
+            chrono::Utc::now(),
+            text,
+        )
+    })?;
+
+    channel
+        .write_message(&SysRaven::Stored)
+        .context("Confirming the message was stored")
 }
 
-fn message(config: &Config, sender: String, text: String) -> Result<()> {
-    let mut mailbox = MailBox::open(&config).context("Opening the mailbox")?; // Opens the mailbox to save the received messages
-    mailbox.add_message(sender, chrono::Utc::now(), text);
-    mailbox.save(&config)?;
+/// Keeps a clipboard in the mailbox and, if the sender is trusted to, puts it on the clipboard right away.
+fn clipboard(
+    config: &Config,
+    channel: &mut Channel<TcpStream>,
+    helo: &Helo,
+    sender: &Sender,
+    text: String,
+    mime: String,
+    paste: bool,
+) -> Result<()> {
+    // The MIME type ends up as an argument of the clipboard tool
+    if !clipboard::is_mime(&mime) {
+        return refuse(channel, config, helo, sender, Rejection::InvalidMime(mime));
+    }
+
+    let copied = paste.then(|| text.clone());
+    MailBox::update(config, |mailbox| {
+        mailbox.add_clipboard(
+            sender.from.clone(),
+            sender.address.clone(),
+            chrono::Utc::now(),
+            text,
+            mime.clone(),
+        )
+    })?;
+
+    channel
+        .write_message(&SysRaven::Stored)
+        .context("Confirming the clipboard was stored")?;
+
+    // Only once the sender has its answer: the clipboard is still kept in the mailbox if it can't be reached
+    if let Some(text) = copied {
+        match Clipboard::new(config).and_then(|clipboard| clipboard.copy(&text, &mime)) {
+            Ok(()) => println!(
+                "Put the clipboard from {} on the clipboard",
+                &sender.address
+            ),
+            Err(e) => eprintln!(
+                "Failed to put the clipboard from {} on the clipboard: {:#}",
+                &sender.address, e
+            ),
+        }
+    }
+
     Ok(())
 }
 
-fn file(config: &Config, sender: String, name: String, content: Vec<u8>) -> Result<()> {
+/// Receives a file streamed after its raven.
+/// The chunks are written to a partial file in the data folder as they arrive, which is renamed once the transfer finishes.
+///
+/// If the transfer is interrupted the partial file is kept, so when the sender sends the same file again
+/// (same transfer `id` and content `hash`), the receiver asks it to resume from where it stopped.
+///
+/// A file that is part of a batch is kept in the mailbox with the id of the `batch`.
+#[allow(clippy::too_many_arguments)]
+fn file(
+    config: &Config,
+    channel: &mut Channel<TcpStream>,
+    sender: &Sender,
+    id: String,
+    name: String,
+    size: u64,
+    hash: String,
+    batch: Option<&str>,
+) -> Result<()> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid transfer id `{}`", id);
+    }
+    // The sender picks the name, it must never lead the file out of the data folder
+    let name = util::safe_filename(&name)?;
+
     // Gets the folder where the files will be stored and ensures that it exists
     let raven_arrivals = format!("{}/data", &config.raven_home);
-    if let Err(e) = util::ensure_folder(&raven_arrivals).context("Failed to create the folder to store files") {
+    let raven_partials = format!("{}/{}", &raven_arrivals, util::PARTIALS_FOLDER);
+    if let Err(e) =
+        util::ensure_folder(&raven_partials).context("Failed to create the folder to store files")
+    {
+        bail!("{}", e);
+    }
+
+    // Finds out how much of the content already arrived in a previous transfer
+    let partial = format!("{}/{}.part", raven_partials, id);
+    let offset = MailBox::update(config, |mailbox| {
+        if mailbox.has_partial(&id, &hash) {
+            std::fs::metadata(&partial)
+                .map(|meta| meta.len())
+                .unwrap_or(0)
+                .min(size)
+        } else {
+            mailbox.add_partial(
+                id.clone(),
+                hash.clone(),
+                sender.from.clone(),
+                sender.address.clone(),
+                chrono::Utc::now(),
+                name.clone(),
+                size,
+                partial.clone(),
+            );
+            0
+        }
+    })?;
+
+    channel
+        .write_message(&SysRaven::Resume { offset })
+        .context("Answering the resume offset")?;
+    // Only the rest of the content travels
+    refund(config, sender, offset)?;
+
+    // Streams the rest of the content to the partial file
+    let received = match stream_to_file(channel, &partial, offset, size) {
+        Ok(received) => received,
+        Err(e) => {
+            if let Some(ProtocolError::StreamTooLong(_)) = e.downcast_ref::<ProtocolError>() {
+                // The sender lied about the size, what arrived can't be trusted to be resumed
+                let _ = std::fs::remove_file(&partial);
+                MailBox::update(config, |mailbox| {
+                    mailbox.remove_partial(&id);
+                    mailbox.add_rejection(
+                        sender.from.clone(),
+                        sender.address.clone(),
+                        chrono::Utc::now(),
+                        Kind::File,
+                        Some(name),
+                        size,
+                        e.to_string(),
+                    )
+                })?;
+
+                bail!("Failed to receive the file: {}", e);
+            }
+
+            bail!(
+                "Failed to receive the file, the partial transfer was kept: {}",
+                e
+            );
+        }
+    };
+
+    let sent = match channel.read_message::<SysRaven>() {
+        Ok(SysRaven::Trailer { hash }) => hash,
+        Ok(other) => bail!("Expected the hash of the file but {:?} arrived", other),
+        Err(e) => bail!(
+            "Failed to receive the hash of the file, the partial transfer was kept: {}",
+            e
+        ),
+    };
+
+    // What arrived must be what was sent, and what was sent what was announced
+    if received != sent || sent != hash {
+        MailBox::update(config, |mailbox| mailbox.remove_partial(&id))?;
+        return quarantine(config, channel, sender, &partial, Kind::File, &name, size);
+    }
+
+    // Gets a non colliding filename and moves the finished file there
+    // Holding the mailbox, so two files with the same name arriving together don't pick the same filename
+    MailBox::update(config, |mailbox| {
+        let path = format!("{}/{}", raven_arrivals, name);
+        let path = util::non_colliding_filename(&path);
+        util::ensure_inside(&raven_arrivals, &path)?;
+        if let Err(e) = std::fs::rename(&partial, &path).context("Saving the received file") {
+            bail!("Failed to write the file: {}", e);
+        }
+
+        mailbox.remove_partial(&id);
+        mailbox.add_file(
+            sender.from.clone(),
+            sender.address.clone(),
+            chrono::Utc::now(),
+            path,
+            batch.map(String::from),
+            Some(hash),
+        );
+        Ok(())
+    })??;
+
+    channel
+        .write_message(&SysRaven::Stored)
+        .context("Confirming the file was stored")
+}
+
+/// Receives a directory, whose files are streamed after its raven.
+/// The directory is built in the partials folder and moved to the data folder once all of it arrived,
+/// an interrupted transfer leaves nothing behind.
+fn directory(
+    config: &Config,
+    channel: &mut Channel<TcpStream>,
+    sender: &Sender,
+    name: String,
+    size: u64,
+    entries: Vec<DirEntry>,
+) -> Result<()> {
+    let name = util::safe_filename(&name)?;
+    directory::check(&entries, size)?;
+
+    let raven_arrivals = format!("{}/data", &config.raven_home);
+    let raven_partials = format!("{}/{}", &raven_arrivals, util::PARTIALS_FOLDER);
+    if let Err(e) =
+        util::ensure_folder(&raven_partials).context("Failed to create the folder to store files")
+    {
         bail!("{}", e);
     }
 
-    // Gets a non colliding filename
-    let path = format!("{}/{}", raven_arrivals, name);
-    let path = util::non_colliding_filename(&path);
+    // Named after the connection, so two directories arriving together never share it
+    let staging = format!(
+        "{}/{}.dir",
+        raven_partials,
+        &blake3::hash(sender.address.as_bytes()).to_hex()[..16]
+    );
+    let _ = std::fs::remove_dir_all(&staging);
 
-    // Writes the file to the disk
-    if let Err(e) = std::fs::write(&path, content).context("Saving the received file") {
-        bail!("Failed to write the file: {}", e);
+    // The files are streamed in chunks again
+    channel.limit_frames(CHUNK_SIZE as u64 + RAVEN_OVERHEAD);
+    let corrupted = match directory::extract(channel, &staging, &entries) {
+        Ok(corrupted) => corrupted,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            bail!("Failed to receive the directory: {}", e);
+        }
+    };
+    if !corrupted.is_empty() {
+        println!(
+            "Files of the directory from {} don't match their hash: {}",
+            &sender.address,
+            corrupted.join(", ")
+        );
+        return quarantine(
+            config,
+            channel,
+            sender,
+            &staging,
+            Kind::Directory,
+            &name,
+            size,
+        );
+    }
+
+    // Kept to check later the directory is still what arrived
+    let hash = match directory::digest(&staging) {
+        Ok(hash) => hash,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&staging);
+            bail!("Failed to hash the directory: {}", e);
+        }
+    };
+
+    // Holding the mailbox, so two directories with the same name arriving together don't pick the same path
+    MailBox::update(config, |mailbox| {
+        let path = util::non_colliding_filename(&format!("{}/{}", raven_arrivals, name));
+        if let Err(e) = util::ensure_inside(&raven_arrivals, &path)
+            .and_then(|_| std::fs::rename(&staging, &path).context("Saving the received directory"))
+        {
+            let _ = std::fs::remove_dir_all(&staging);
+            bail!("Failed to write the directory: {}", e);
+        }
+
+        mailbox.add_file(
+            sender.from.clone(),
+            sender.address.clone(),
+            chrono::Utc::now(),
+            path,
+            None,
+            Some(hash),
+        );
+        Ok(())
+    })??;
+
+    channel
+        .write_message(&SysRaven::Stored)
+        .context("Confirming the directory was stored")
+}
+
+/// Receives the files of a batch one after the other, each one just like a file raven.
+/// A file over `max_file` is refused on its own and the sender skips it, the rest of the batch still arrives.
+/// Every file is confirmed or refused once it arrived, so the sender knows which ones made it if the transfer breaks.
+#[allow(clippy::too_many_arguments)]
+fn batch(
+    config: &Config,
+    channel: &mut Channel<TcpStream>,
+    helo: &Helo,
+    sender: &Sender,
+    limits: &Limits,
+    id: String,
+    files: Vec<BatchFile>,
+) -> Result<()> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid batch id `{}`", id);
+    }
+
+    // The files are streamed in chunks again
+    channel.limit_frames(CHUNK_SIZE as u64 + RAVEN_OVERHEAD);
+
+    let count = files.len();
+    for (i, f) in files.into_iter().enumerate() {
+        let reason = if util::safe_filename(&f.name).is_err() {
+            Some(Rejection::UnsafeName(f.name.clone()))
+        } else {
+            limits
+                .max_file
+                .filter(|max| f.size > *max)
+                .map(|max| Rejection::TooLarge { max })
+        };
+        if let Some(reason) = reason {
+            let announced = Helo {
+                kind: Kind::File,
+                name: Some(f.name),
+                size: f.size,
+                ..helo.clone()
+            };
+            refuse(channel, config, &announced, sender, reason)?;
+            refund(config, sender, announced.size)?;
+            continue;
+        }
+
+        file(
+            config,
+            channel,
+            sender,
+            f.id,
+            f.name,
+            f.size,
+            f.hash,
+            Some(&id),
+        )?;
+        println!(
+            "Received file {} of {} of the batch {} from {}",
+            i + 1,
+            count,
+            id,
+            &sender.address
+        );
     }
 
-    let mut mailbox = MailBox::open(&config).context("Opening the mailbox")?; // Opens the mailbox to save the received messages
-    mailbox.add_file(sender, chrono::Utc::now(), path);
-    mailbox.save(&config)?;
-    
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Appends the streamed content to the file at `path` starting at `offset`, checking that the file ends up with `size` bytes.
+///
+/// Returns the hash of the whole file, the content already there hashed before the one streamed.
+fn stream_to_file(
+    channel: &mut Channel<TcpStream>,
+    path: &str,
+    offset: u64,
+    size: u64,
+) -> Result<String> {
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(path)
+        .context(format!("Opening {}", path))?;
+    f.set_len(offset)?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut (&mut f).take(offset), &mut hasher).context(format!("Hashing {}", path))?;
+    f.seek(SeekFrom::Start(offset))?;
+    let mut f = Hashed::resume(BufWriter::new(f), hasher);
+
+    let received = channel.read_stream(&mut f, size - offset)?;
+    if offset + received != size {
+        bail!("Expected {} bytes but {} arrived", size, offset + received);
+    }
+
+    Ok(f.hash())
+}
+
+/// Moves a file or a directory whose content doesn't match the hash of what was sent to the quarantine folder,
+/// out of the data folder, and refuses it. The refusal is logged in the mailbox with where it was moved.
+fn quarantine(
+    config: &Config,
+    channel: &mut Channel<TcpStream>,
+    sender: &Sender,
+    path: &str,
+    kind: Kind,
+    name: &str,
+    size: u64,
+) -> Result<()> {
+    let quarantine = format!("{}/{}", &config.raven_home, QUARANTINE_FOLDER);
+    util::ensure_folder(&quarantine).context("Failed to create the quarantine folder")?;
+
+    // Holding the mailbox, so two ravens quarantined together don't pick the same path
+    let quarantined = MailBox::update(config, |mailbox| {
+        let quarantined = util::non_colliding_filename(&format!("{}/{}", quarantine, name));
+        std::fs::rename(path, &quarantined).context("Quarantining the received content")?;
+
+        mailbox.add_rejection(
+            sender.from.clone(),
+            sender.address.clone(),
+            chrono::Utc::now(),
+            kind,
+            Some(name.to_string()),
+            size,
+            format!("{}, quarantined as {}", Rejection::Corrupted, quarantined),
+        );
+        Ok::<_, anyhow::Error>(quarantined)
+    })??;
+    println!(
+        "Quarantined {} from {} as {}: {}",
+        kind,
+        &sender.address,
+        quarantined,
+        Rejection::Corrupted
+    );
+
+    channel
+        .write_message(&SysRaven::Reject(Rejection::Corrupted))
+        .context("Refusing the raven")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr, TcpListener},
+        sync::Arc,
+        thread,
+    };
+
+    use anyhow::Result;
+
+    use crate::{
+        config::{Config, Limits},
+        peers::Destination,
+        raven::{mailbox::MailBox, protocol::CHUNK_SIZE, send},
+        trust::{Identity, TrustStore},
+        util,
+    };
+
+    fn config(name: &str) -> Config {
+        let mut config = Config::new();
+        config.raven_home = format!(
+            "{}/raven-test-{}-{}",
+            std::env::temp_dir().to_str().unwrap(),
+            name,
+            std::process::id()
+        );
+        let _ = std::fs::remove_dir_all(&config.raven_home);
+
+        config
+    }
+
+    /// Runs a receiver on a loopback port for a single raven, returning where to send it.
+    fn receiver(config: Config) -> (Destination, thread::JoinHandle<Result<()>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let to = Destination::new(
+            Ipv4Addr::LOCALHOST.into(),
+            listener.local_addr().unwrap().port(),
+        );
+
+        let handle = thread::spawn(move || {
+            let identity = Arc::new(Identity::load(&config)?);
+            let (stream, _) = listener.accept()?;
+            super::receive(stream, Arc::new(config), identity)
+        });
+
+        (to, handle)
+    }
+
+    /// A receiver and a sender, each with its own raven home, and the identity of the sender.
+    struct Peers {
+        receiving: Config,
+        sending: Config,
+        identity: Identity,
+    }
+
+    impl Peers {
+        fn new(name: &str) -> Self {
+            let receiving = config(&format!("{}-receiver", name));
+            let sending = config(&format!("{}-sender", name));
+            let identity = Identity::load(&sending).unwrap();
+
+            Self {
+                receiving,
+                sending,
+                identity,
+            }
+        }
+
+        /// Runs a receiver for a single raven and sends it with `send`, returning what was sent once it was received.
+        fn deliver<T>(&self, send: impl FnOnce(&Destination) -> Result<T>) -> Result<T> {
+            let (to, handle) = receiver(self.receiving.clone());
+            let sent = send(&to);
+            handle.join().unwrap().unwrap();
+
+            sent
+        }
+
+        /// A path in the raven home of the receiver.
+        fn received(&self, path: &str) -> String {
+            format!("{}/{}", self.receiving.raven_home, path)
+        }
+
+        /// The mailbox of the receiver, as written on the disk.
+        fn mailbox(&self) -> String {
+            std::fs::read_to_string(self.received("mailbox.toml")).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_encrypted_ravens() {
+        let peers = Peers::new("encrypted");
+        let Peers {
+            sending, identity, ..
+        } = &peers;
+
+        peers
+            .deliver(|to| send::send(to, "top secret".into(), sending, identity))
+            .unwrap();
+        assert!(peers.mailbox().contains("top secret"));
+
+        let file = format!("{}/secret.txt", sending.raven_home);
+        std::fs::write(&file, "top secret file").unwrap();
+        peers
+            .deliver(|to| send::send_file(to, file, sending, identity))
+            .unwrap();
+
+        let received = std::fs::read_to_string(peers.received("data/secret.txt")).unwrap();
+        assert_eq!(received, "top secret file");
+        assert!(peers.mailbox().contains("top secret"));
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let Peers {
+            receiving,
+            sending,
+            identity,
+        } = Peers::new("dual-stack");
+
+        let listener = super::listen("::", 0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let identity = Arc::new(Identity::load(&receiving)?);
+            let config = Arc::new(receiving);
+            for _ in 0..2 {
+                let (stream, _) = listener.accept()?;
+                super::receive(stream, Arc::clone(&config), Arc::clone(&identity))?;
+            }
+            Ok::<_, anyhow::Error>(config)
+        });
+
+        send::send(
+            &Destination::new(Ipv4Addr::LOCALHOST.into(), port),
+            "over ipv4".into(),
+            &sending,
+            &identity,
+        )
+        .unwrap();
+        send::send(
+            &Destination::new(Ipv6Addr::LOCALHOST.into(), port),
+            "over ipv6".into(),
+            &sending,
+            &identity,
+        )
+        .unwrap();
+        let receiving = handle.join().unwrap().unwrap();
+
+        let mailbox =
+            std::fs::read_to_string(format!("{}/mailbox.toml", receiving.raven_home)).unwrap();
+        assert!(mailbox.contains("address = \"127.0.0.1:"));
+        assert!(mailbox.contains("address = \"[::1]:"));
+    }
+
+    #[test]
+    fn test_next_address() {
+        let peers = Peers::new("next-address");
+
+        // Nothing listens on the ipv6 loopback, the ipv4 one is tried next
+        let sent = peers
+            .deliver(|to| {
+                let mut to = to.clone();
+                to.addresses.insert(0, Ipv6Addr::LOCALHOST.into());
+                send::send(&to, "hi".into(), &peers.sending, &peers.identity)
+            })
+            .unwrap();
+        assert_eq!(sent.to, "127.0.0.1");
+    }
+
+    #[test]
+    fn test_refuse_unencrypted() {
+        let mut peers = Peers::new("plaintext");
+        peers.receiving.receiver.require_encryption = true;
+        peers.sending.sender.encrypt = false;
+
+        let err = peers
+            .deliver(|to| send::send(to, "hi".into(), &peers.sending, &peers.identity))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("only encrypted ravens are accepted"));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut peers = Peers::new("limits");
+        peers.receiving.receiver.limits.max_message = Some(8);
+        peers.receiving.receiver.limits.max_daily = Some(12);
+        let send = |text: &str, sending: &Config, identity: &Identity| {
+            peers.deliver(|to| send::send(to, text.into(), sending, identity))
+        };
+
+        let err = send("way too long", &peers.sending, &peers.identity).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+
+        for _ in 0..2 {
+            send("short", &peers.sending, &peers.identity).unwrap();
+        }
+
+        // The third short message goes over the daily quota
+        let err = send("short", &peers.sending, &peers.identity).unwrap_err();
+        assert!(err.to_string().contains("daily"));
+
+        // An untrusted sender can't get a new quota with a new key
+        let another = config("limits-another-sender");
+        let identity = Identity::load(&another).unwrap();
+        let err = send("short", &another, &identity).unwrap_err();
+        assert!(err.to_string().contains("daily"));
+
+        // Refusals are logged in the mailbox
+        assert_eq!(peers.mailbox().matches("[[rejections]]").count(), 3);
+    }
+
+    #[test]
+    fn test_large_message() {
+        let mut peers = Peers::new("large-message");
+
+        // Bigger than a chunk, and hardly compressible
+        let mut seed = 7u32;
+        let text: String = (0..CHUNK_SIZE + 36 * 1024)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (b'!' + (seed >> 16) as u8 % 94) as char
+            })
+            .collect();
+
+        for compress in [true, false] {
+            peers.sending.sender.compress = compress;
+            peers
+                .deliver(|to| send::send(to, text.clone(), &peers.sending, &peers.identity))
+                .unwrap();
+        }
+
+        assert_eq!(peers.mailbox().matches(text.as_str()).count(), 2);
+    }
+
+    #[test]
+    fn test_compressed_ravens() {
+        let mut peers = Peers::new("compressed");
+
+        let file = format!("{}/raven.log", peers.sending.raven_home);
+        let content = "the raven flew over the buildings\n".repeat(32 * 1024);
+        std::fs::write(&file, &content).unwrap();
+
+        for compress in [true, false] {
+            peers.sending.sender.compress = compress;
+            peers
+                .deliver(|to| send::send_file(to, file.clone(), &peers.sending, &peers.identity))
+                .unwrap();
+
+            let received = std::fs::read_dir(peers.received("data"))
+                .unwrap()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_file())
+                .all(|entry| std::fs::read_to_string(entry.path()).unwrap() == content);
+            assert!(received);
+        }
+    }
+
+    #[test]
+    fn test_clipboard() {
+        let mut peers = Peers::new("clipboard");
+        let copied = peers.received("copied");
+        peers.receiving.clipboard.paste = Some(vec!["true".into()]);
+        peers.receiving.clipboard.copy = Some(vec![
+            "sh".into(),
+            "-c".into(),
+            "cat > \"$0\"".into(),
+            copied.clone(),
+        ]);
+        let Peers {
+            receiving,
+            sending,
+            identity,
+        } = &peers;
+        let send = |text: &str, mime: &str| {
+            peers
+                .deliver(|to| send::send_clipboard(to, text.into(), mime.into(), sending, identity))
+        };
+
+        // Untrusted senders only reach the mailbox
+        send("<b>hi</b>", "text/html").unwrap();
+        assert!(!std::path::Path::new(&copied).exists());
+
+        let mut trust = TrustStore::default();
+        trust
+            .add(
+                "laptop".into(),
+                identity.public_key(),
+                None,
+                Limits::default(),
+                true,
+            )
+            .unwrap();
+        trust.save(receiving).unwrap();
+
+        send("copied", "text/plain").unwrap();
+        assert_eq!(std::fs::read_to_string(&copied).unwrap(), "copied");
+
+        // A clipboard bigger than a chunk still arrives whole
+        let text = "a long copied line\n".repeat(CHUNK_SIZE / 8);
+        send(&text, "text/plain").unwrap();
+        assert_eq!(std::fs::read_to_string(&copied).unwrap(), text);
+
+        // A MIME type that could pass for an option of the tool is refused
+        let err = send("x", "-o/x").unwrap_err();
+        assert!(err.to_string().contains("isn't a MIME type"));
+        assert_eq!(std::fs::read_to_string(&copied).unwrap(), text);
+
+        let mailbox = peers.mailbox();
+        assert!(mailbox.contains("mime = \"text/html\""));
+        assert!(mailbox.contains("mime = \"text/plain\""));
+    }
+
+    #[test]
+    fn test_directory() {
+        let peers = Peers::new("directory");
+
+        let dir = format!("{}/project", peers.sending.raven_home);
+        std::fs::create_dir_all(format!("{}/src", dir)).unwrap();
+        std::fs::write(format!("{}/README.md", dir), "# project").unwrap();
+        std::fs::write(format!("{}/src/main.rs", dir), "fn main() {}").unwrap();
+
+        peers
+            .deliver(|to| send::send_dir(to, dir, &peers.sending, &peers.identity))
+            .unwrap();
+
+        let received = peers.received("data/project");
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/README.md", received)).unwrap(),
+            "# project"
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("{}/src/main.rs", received)).unwrap(),
+            "fn main() {}"
+        );
+
+        // Nothing is left behind in the partials folder
+        let partials = std::fs::read_dir(peers.received("data/.partial")).unwrap();
+        assert_eq!(partials.count(), 0);
+    }
+
+    #[test]
+    fn test_hostile_names() {
+        let peers = Peers::new("hostile");
+
+        let file = format!("{}/payload", peers.sending.raven_home);
+        std::fs::write(&file, "owned").unwrap();
+        let send = |name: &str| {
+            peers.deliver(|to| {
+                send::send_path(to, &file, name.into(), &peers.sending, &peers.identity)
+            })
+        };
+
+        // Anything before the last separator is dropped, the file lands in the data folder
+        for name in [
+            "../../escaped",
+            "/tmp/escaped",
+            "..\\..\\escaped",
+            "escaped. ",
+        ] {
+            send(name).unwrap();
+        }
+        let data = peers.received("data");
+        for file in ["escaped", "escaped_1", "escaped_2", "escaped_3"] {
+            assert_eq!(
+                std::fs::read_to_string(format!("{}/{}", data, file)).unwrap(),
+                "owned"
+            );
+        }
+        assert!(!std::path::Path::new(&peers.received("../escaped")).exists());
+
+        // Names that can't be made safe are refused before anything is sent
+        for name in [
+            "..",
+            "",
+            "nul.txt",
+            ".partial",
+            "bad\nname",
+            "tab\tname",
+            "\u{1b}[2J",
+        ] {
+            let err = send(name).unwrap_err();
+            assert!(
+                err.to_string().contains("isn't allowed"),
+                "{:?}: {}",
+                name,
+                err
+            );
+        }
+
+        assert_eq!(peers.mailbox().matches("[[rejections]]").count(), 7);
+        assert_eq!(std::fs::read_dir(&data).unwrap().count(), 5);
+    }
+
+    #[test]
+    fn test_corrupted_resume() {
+        let peers = Peers::new("corrupted");
+
+        let file = format!("{}/notes.txt", peers.sending.raven_home);
+        std::fs::write(&file, "the raven flew over the buildings").unwrap();
+        let hash = util::hash_file(&file).unwrap();
+        let id = send::transfer_id(&file, &hash);
+
+        // The beginning of an interrupted transfer got corrupted on the disk of the receiver
+        let partial = peers.received(&format!("data/.partial/{}.part", id));
+        std::fs::create_dir_all(peers.received("data/.partial")).unwrap();
+        std::fs::write(&partial, "THE RAVEN").unwrap();
+        MailBox::update(&peers.receiving, |mailbox| {
+            mailbox.add_partial(
+                id.clone(),
+                hash.clone(),
+                "laptop".into(),
+                "127.0.0.1".into(),
+                chrono::Utc::now(),
+                "notes.txt".into(),
+                33,
+                partial.clone(),
+            )
+        })
+        .unwrap();
+
+        let send = || {
+            peers.deliver(|to| send::send_file(to, file.clone(), &peers.sending, &peers.identity))
+        };
+        let err = send().unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't match its hash"),
+            "{}",
+            err
+        );
+
+        let quarantined = std::fs::read_to_string(peers.received("quarantine/notes.txt")).unwrap();
+        assert_eq!(quarantined, "THE RAVEN flew over the buildings");
+        assert!(!std::path::Path::new(&peers.received("data/notes.txt")).exists());
+        let mailbox = peers.mailbox();
+        assert!(mailbox.contains("quarantined as"));
+        assert!(!mailbox.contains("[[partials]]"));
+
+        // Sent again, it starts from scratch and arrives intact, with its hash
+        let sent = send().unwrap();
+        assert_eq!(sent.resumed_from, Some(0));
+        assert_eq!(sent.hash.as_deref(), Some(hash.as_str()));
+        assert!(peers.mailbox().contains(&format!("hash = \"{}\"", hash)));
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut peers = Peers::new("batch");
+        peers.receiving.receiver.limits.max_file = Some(16);
+
+        let docs = format!("{}/docs", peers.sending.raven_home);
+        std::fs::create_dir_all(&docs).unwrap();
+        std::fs::write(format!("{}/a.txt", docs), "first").unwrap();
+        std::fs::write(format!("{}/b.txt", docs), "second").unwrap();
+        std::fs::write(format!("{}/huge.log", docs), "way over the max file size").unwrap();
+
+        let files = vec![
+            format!("{}/*.txt", docs),
+            format!("{}/huge.log", docs),
+            format!("{}/missing.txt", docs),
+        ];
+        let batch = peers
+            .deliver(|to| send::send_batch(to, files, &peers.sending, &peers.identity))
+            .unwrap();
+
+        let sent = batch
+            .sent
+            .iter()
+            .map(|sent| sent.name.clone().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sent, ["a.txt", "b.txt"]);
+        let failed = batch
+            .failed
+            .iter()
+            .map(|failed| util::basename(&failed.file))
+            .collect::<Vec<_>>();
+        assert_eq!(failed, ["missing.txt", "huge.log"]);
+        assert!(batch.failed[1].reason.contains("too large"));
+
+        assert_eq!(
+            std::fs::read_to_string(peers.received("data/a.txt")).unwrap(),
+            "first"
+        );
+        assert_eq!(
+            std::fs::read_to_string(peers.received("data/b.txt")).unwrap(),
+            "second"
+        );
+        assert!(!std::path::Path::new(&peers.received("data/huge.log")).exists());
+
+        // Both files are linked by the batch, the refused one is logged
+        let mailbox = peers.mailbox();
+        assert_eq!(
+            mailbox
+                .matches(&format!("batch = \"{}\"", batch.batch))
+                .count(),
+            2
+        );
+        assert_eq!(mailbox.matches("[[rejections]]").count(), 1);
+    }
+}
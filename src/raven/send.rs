@@ -1,68 +1,748 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    io::{BufReader, Read},
+    net::{SocketAddr, TcpStream},
+    path::Path,
 };
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use rand_core::{OsRng, RngCore};
 
-use crate::{raven::Raven, util};
+use crate::{
+    config::{Config, Peer},
+    output::{BatchRecord, FailedRecord, Sent},
+    peers::Destination,
+    raven::{
+        self,
+        compression::{self, Compression},
+        crypto::{Ephemeral, Role},
+        directory,
+        protocol::{Channel, Hashed, Header, Kind},
+        BatchFile, Helo, Raven, Rejection, SysRaven,
+    },
+    trust::{self, Identity, TrustStore},
+    util,
+};
+
+/// The biggest input piped to stdin that is sent as a text message, anything bigger is sent as a file.
+const MAX_PIPED_TEXT: u64 = 64 * 1024;
 
 /// Sends a message by a raven to another client.
-/// The target client is specified by the `to` ipv4 address and `port`. The message is a `String`.
+/// The target client is the destination `to`, as `peers::resolve` found it. The message is a `String`.
 /// It will send only one message and finishes, the TCP protocol will take care of the rest.
-/// If the target is offline, the connection will fail and the function will return an error.
-pub fn send(to: &str, port: u16, message: String) -> Result<()> {
-    if !util::is_ipv4_address(to) {
-        bail!("Invalid ipv4 address {}", to);
-    }
+/// If the target is offline or refuses the raven, the function will return an error.
+///
+/// The progress is reported on stderr, so stdout only holds the returned record once printed.
+pub fn send(
+    to: &Destination,
+    message: String,
+    config: &Config,
+    identity: &Identity,
+) -> Result<Sent> {
+    deliver(to, Raven::Text { text: message }, config, identity)
+}
 
-    let mut stream = TcpStream::connect(format!("{}:{}", to, port)).context(format!(
-        "Connecting to the target client at {}:{}",
-        to, port
-    ))?;
-    println!("Connected to {}:{}", to, port);
+/// Sends the `text` taken from the clipboard, of the given `mime` type, by a raven to another client.
+/// The receiver keeps it in the mailbox and may put it on its own clipboard.
+pub fn send_clipboard(
+    to: &Destination,
+    text: String,
+    mime: String,
+    config: &Config,
+    identity: &Identity,
+) -> Result<Sent> {
+    deliver(to, Raven::Clipboard { text, mime }, config, identity)
+}
 
-    let rv = Raven::Text { text: message };
-    let encoded = bincode::serialize(&rv).context("Serializing message")?;
+/// Sends a raven whose content fits in a single frame, a message or a clipboard.
+fn deliver(to: &Destination, rv: Raven, config: &Config, identity: &Identity) -> Result<Sent> {
+    let (stream, reached) = connect(to)?;
 
-    stream.write_all(&encoded)?;
-    println!("Message sent: {:?}", rv);
+    let (text, mime) = match &rv {
+        Raven::Text { text } => (text, None),
+        Raven::Clipboard { text, mime } => (text, Some(mime.clone())),
+        Raven::File { .. } | Raven::Directory { .. } | Raven::Batch { .. } => {
+            bail!("Files and directories are streamed by `send_file`, `send_batch` and `send_dir`")
+        }
+    };
+    let size = text.len() as u64;
 
-    Ok(())
+    let helo = Helo {
+        kind: rv.kind(),
+        name: None,
+        size,
+        from: config.name.clone(),
+        key: identity.public_key(),
+        ephemeral: None,
+        compression: offered(config),
+    };
+    let mut channel = fly(stream, reached, helo, to, config, identity)?;
+
+    channel
+        .write_message(&rv)
+        .context("Writing the raven to the TCP stream")?;
+    if let Some(reason) = confirmation(&mut channel)? {
+        bail!("The receiver refused the {}: {}", rv.kind(), reason);
+    }
+
+    Ok(Sent {
+        to: reached.ip().to_string(),
+        port: reached.port(),
+        kind: rv.kind(),
+        name: None,
+        size,
+        hash: None,
+        mime,
+        resumed_from: None,
+        encrypted: channel.is_encrypted(),
+        compression: channel.compression(),
+    })
 }
 
 /// Sends a file by a raven to another client.
-/// The target client is specified by the `to` ipv4 address and `port`. The file is a `String` with the file path.
+/// The target client is the destination `to`, as `peers::resolve` found it. The file is a `String` with the file path.
 /// It will send only one file and finishes, the TCP protocol will take care of the rest.
-/// If the target is offline, the connection will fail and the function will return an error.
+/// The file is streamed in chunks, so it's never fully loaded in memory.
+/// If a previous transfer of the same file was interrupted, the receiver tells where to resume from.
+/// If the target is offline or refuses the raven, the function will return an error.
 /// If the file isn't found, the function will return an error.
-pub fn send_file(to: &str, port: u16, file: String) -> Result<()> {
-    if !util::is_ipv4_address(to) {
-        bail!("Invalid address");
+pub fn send_file(
+    to: &Destination,
+    file: String,
+    config: &Config,
+    identity: &Identity,
+) -> Result<Sent> {
+    let name = util::basename(&file).to_string();
+
+    send_path(to, &file, name, config, identity)
+}
+
+/// Sends many files by a single raven to another client, over one connection and one handshake.
+/// `files` may hold glob patterns (e.g. `docs/*.pdf`), which are expanded to the files they match.
+///
+/// The receiver keeps every file in the mailbox with the same batch id. A file that can't be read or that
+/// the receiver refuses doesn't stop the others, the returned record tells which files were sent and which failed.
+/// If the connection breaks, the file in flight and the ones after it are reported as failed.
+/// If none of the files can be sent, the function will return an error.
+pub fn send_batch(
+    to: &Destination,
+    files: Vec<String>,
+    config: &Config,
+    identity: &Identity,
+) -> Result<BatchRecord> {
+    let mut failed = Vec::new();
+    let mut ready = Vec::new();
+    for file in expand(&files, &mut failed) {
+        match batch_file(&file) {
+            Ok(batched) => ready.push((file, batched)),
+            Err(e) => failed.push(FailedRecord {
+                file,
+                reason: format!("{:#}", e),
+            }),
+        }
     }
+    if ready.is_empty() {
+        let reasons = failed
+            .iter()
+            .map(|failed| format!("{}: {}", failed.file, failed.reason))
+            .collect::<Vec<_>>();
+        bail!("Nothing to send ({})", reasons.join(", "));
+    }
+
+    let (stream, reached) = connect(to)?;
+
+    let mut id = [0u8; 8];
+    OsRng.fill_bytes(&mut id);
+    let batch = hex::encode(id);
+
+    let helo = Helo {
+        kind: Kind::Batch,
+        name: None,
+        size: ready.iter().map(|(_, batched)| batched.size).sum(),
+        from: config.name.clone(),
+        key: identity.public_key(),
+        ephemeral: None,
+        // Compressing already compressed files only wastes time
+        compression: if ready
+            .iter()
+            .all(|(file, _)| compression::is_compressed(file))
+        {
+            Vec::new()
+        } else {
+            offered(config)
+        },
+    };
+    let mut channel = fly(stream, reached, helo, to, config, identity)?;
+
+    let rv = Raven::Batch {
+        id: batch.clone(),
+        files: ready.iter().map(|(_, batched)| batched.clone()).collect(),
+    };
+    channel
+        .write_message(&rv)
+        .context("Writing the raven to the TCP stream")?;
 
-    let mut stream = TcpStream::connect(format!("{}:{}", to, port)).context(format!(
-        "Connecting to the target client at {}:{}",
-        to, port
-    ))?;
-    println!("Connected to {}:{}", to, port);
+    let count = ready.len();
+    let mut sent = Vec::new();
+    let mut ready = ready.into_iter().enumerate();
+    while let Some((i, (file, batched))) = ready.next() {
+        let delivered = match channel.read_message().context("Reading the resume offset") {
+            Ok(SysRaven::Resume { offset }) => std::fs::File::open(&file)
+                .context(format!("Opening file {} to be sent", file))
+                .and_then(|f| stream_file(&mut channel, f, &file, batched.size, offset))
+                .and_then(|hash| {
+                    Ok(match confirmation(&mut channel)? {
+                        Some(reason) => Err(reason),
+                        None => Ok((offset, hash)),
+                    })
+                })
+                .context("The transfer broke"),
+            Ok(SysRaven::Reject(reason)) => Ok(Err(reason)),
+            Ok(reply) => Err(anyhow!(
+                "Expected the resume offset but the receiver answered {:?}",
+                reply
+            )),
+            Err(e) => Err(e),
+        };
+
+        match delivered {
+            Ok(Ok((offset, hash))) => {
+                eprintln!(
+                    "[{}/{}] Sent {} ({} bytes)",
+                    i + 1,
+                    count,
+                    file,
+                    batched.size
+                );
+                sent.push(Sent {
+                    to: reached.ip().to_string(),
+                    port: reached.port(),
+                    kind: Kind::File,
+                    name: Some(batched.name),
+                    size: batched.size,
+                    hash: Some(hash),
+                    mime: None,
+                    resumed_from: Some(offset),
+                    encrypted: channel.is_encrypted(),
+                    compression: channel.compression(),
+                });
+            }
+            Ok(Err(reason)) => {
+                eprintln!(
+                    "[{}/{}] The receiver refused {}: {}",
+                    i + 1,
+                    count,
+                    file,
+                    reason
+                );
+                failed.push(FailedRecord {
+                    file,
+                    reason: format!("The receiver refused it: {}", reason),
+                });
+            }
+            Err(e) => {
+                // Nothing more can go through the connection
+                eprintln!("[{}/{}] Failed to send {}: {:#}", i + 1, count, file, e);
+                failed.push(FailedRecord {
+                    file: file.clone(),
+                    reason: format!("{:#}", e),
+                });
+                failed.extend(ready.map(|(_, (rest, _))| FailedRecord {
+                    file: rest,
+                    reason: format!("Not sent, the transfer broke while sending {}", file),
+                }));
+                break;
+            }
+        }
+    }
+
+    Ok(BatchRecord {
+        to: reached.ip().to_string(),
+        port: reached.port(),
+        batch,
+        sent,
+        failed,
+    })
+}
+
+/// Whether `file` is a glob pattern to expand rather than the path of a file.
+/// A file may be named like a pattern, so it's only a pattern if no such file exists.
+pub fn is_pattern(file: &str) -> bool {
+    file.contains(['*', '?', '[']) && !Path::new(file).exists()
+}
+
+/// Expands the glob patterns among `files` to the files they match, leaving the rest as they are.
+/// The patterns that match nothing are reported as failed.
+fn expand(files: &[String], failed: &mut Vec<FailedRecord>) -> Vec<String> {
+    let mut expanded = Vec::new();
+
+    for file in files {
+        if !is_pattern(file) {
+            expanded.push(file.clone());
+            continue;
+        }
+
+        let matches = glob::glob(file).map(|paths| {
+            paths
+                .filter_map(|path| path.ok())
+                .filter_map(|path| path.to_str().map(String::from))
+                .collect::<Vec<_>>()
+        });
+        match matches {
+            Ok(matches) if !matches.is_empty() => expanded.extend(matches),
+            Ok(_) => failed.push(FailedRecord {
+                file: file.clone(),
+                reason: "No file matches the pattern".into(),
+            }),
+            Err(e) => failed.push(FailedRecord {
+                file: file.clone(),
+                reason: format!("Invalid pattern: {}", e),
+            }),
+        }
+    }
+
+    expanded
+}
+
+/// Describes the file at `file` to be sent in a batch, it must be a readable file.
+fn batch_file(file: &str) -> Result<BatchFile> {
+    let meta =
+        std::fs::metadata(file).context(format!("Reading metadata of file {} to be sent", file))?;
+    if meta.is_dir() {
+        bail!("It's a directory, send it on its own with -r");
+    }
+
+    let hash = util::hash_file(file)?;
+    Ok(BatchFile {
+        id: transfer_id(file, &hash),
+        name: util::basename(file).to_string(),
+        size: meta.len(),
+        hash,
+    })
+}
+
+/// Sends a directory with everything inside it by a raven to another client.
+/// The receiver gets the same tree of folders and files, with their permissions and modification times.
+///
+/// The raven lists the folders and files by their path relative to the directory,
+/// the content of the files follows it, streamed one after the other.
+pub fn send_dir(
+    to: &Destination,
+    dir: String,
+    config: &Config,
+    identity: &Identity,
+) -> Result<Sent> {
+    let name = std::path::Path::new(&dir)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context(format!("{} has no name to send it by", &dir))?
+        .to_string();
+    let entries = directory::manifest(&dir)?;
+    let size = entries.iter().map(|entry| entry.size).sum();
+
+    let (stream, reached) = connect(to)?;
+
+    let helo = Helo {
+        kind: Kind::Directory,
+        name: Some(name.clone()),
+        size,
+        from: config.name.clone(),
+        key: identity.public_key(),
+        ephemeral: None,
+        compression: offered(config),
+    };
+    let mut channel = fly(stream, reached, helo, to, config, identity)?;
+
+    let files = entries.iter().filter(|entry| !entry.folder).count();
+    let rv = Raven::Directory {
+        name: name.clone(),
+        size,
+        entries: entries.clone(),
+    };
+    channel
+        .write_message(&rv)
+        .context("Writing the raven to the TCP stream")?;
+
+    directory::stream(&mut channel, &dir, &entries)?;
+    if let Some(reason) = confirmation(&mut channel)? {
+        bail!("The receiver refused the directory: {}", reason);
+    }
+    eprintln!("Sent {} files", files);
+
+    Ok(Sent {
+        to: reached.ip().to_string(),
+        port: reached.port(),
+        kind: Kind::Directory,
+        name: Some(name),
+        size,
+        hash: None,
+        mime: None,
+        resumed_from: None,
+        encrypted: channel.is_encrypted(),
+        compression: channel.compression(),
+    })
+}
+
+/// Sends what is piped to stdin by a raven to another client.
+/// Short text is sent as a message, anything else (or anything when a `name` is given) as a file
+/// called `name`, `stdin` by default.
+///
+/// Since the size of a raven is announced before it's sent, stdin is first spooled to a private file
+/// in the raven home folder, which is removed once the raven is sent.
+pub fn send_stdin(
+    to: &Destination,
+    name: Option<String>,
+    config: &Config,
+    identity: &Identity,
+) -> Result<Sent> {
+    let spool = format!("{}/.stdin-{}", config.raven_home, std::process::id());
+    let sent = spool_stdin(&spool).and_then(|size| {
+        if size == 0 {
+            bail!("Nothing to send, stdin is empty");
+        }
+
+        let text = if size <= MAX_PIPED_TEXT && name.is_none() {
+            String::from_utf8(std::fs::read(&spool).context("Reading stdin back")?).ok()
+        } else {
+            None
+        };
+
+        match text {
+            Some(text) => send(to, text, config, identity),
+            None => send_path(to, &spool, name.unwrap_or("stdin".into()), config, identity),
+        }
+    });
+    let _ = std::fs::remove_file(&spool);
+
+    sent
+}
 
-    let mut f = std::fs::File::open(&file).context(format!("Opening file {} to be sent", &file))?;
-    let mut content = Vec::new();
-    f.read_to_end(&mut content)
-        .context(format!("Reading file {} to be sent", &file))?;
+/// Copies stdin to the file at `spool`, readable only by its owner, returning how many bytes were copied.
+fn spool_stdin(spool: &str) -> Result<u64> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut f = options
+        .open(spool)
+        .context(format!("Opening {} to spool stdin", spool))?;
+
+    std::io::copy(&mut std::io::stdin().lock(), &mut f).context("Reading stdin")
+}
+
+/// Sends the file at `file` by a raven, announcing it as `name`.
+pub(crate) fn send_path(
+    to: &Destination,
+    file: &str,
+    name: String,
+    config: &Config,
+    identity: &Identity,
+) -> Result<Sent> {
+    let f = std::fs::File::open(file).context(format!("Opening file {} to be sent", file))?;
+    let meta = f
+        .metadata()
+        .context(format!("Reading metadata of file {} to be sent", file))?;
+    if meta.is_dir() {
+        bail!(
+            "{} is a directory, use -r to send it with everything inside",
+            file
+        );
+    }
+    let size = meta.len();
+    // Hashed before connecting, the receiver doesn't wait on a silent connection for long
+    let hash = util::hash_file(file)?;
+
+    let (stream, reached) = connect(to)?;
+
+    let helo = Helo {
+        kind: Kind::File,
+        name: Some(name.clone()),
+        size,
+        from: config.name.clone(),
+        key: identity.public_key(),
+        ephemeral: None,
+        // Compressing an already compressed file only wastes time
+        compression: if compression::is_compressed(file) {
+            Vec::new()
+        } else {
+            offered(config)
+        },
+    };
+    let mut channel = fly(stream, reached, helo, to, config, identity)?;
 
     let rv = Raven::File {
-        name: util::basename(&file).to_string(),
-        content,
+        id: transfer_id(file, &hash),
+        name: name.clone(),
+        size,
+        hash: hash.clone(),
+    };
+    channel
+        .write_message(&rv)
+        .context("Writing the raven to the TCP stream")?;
+
+    let offset = match channel
+        .read_message()
+        .context("Reading the resume offset")?
+    {
+        SysRaven::Resume { offset } => offset,
+        reply => bail!(
+            "Expected the resume offset but the receiver answered {:?}",
+            reply
+        ),
+    };
+    let hash = stream_file(&mut channel, f, file, size, offset)?;
+    if let Some(reason) = confirmation(&mut channel)? {
+        bail!("The receiver refused the file: {}", reason);
+    }
+
+    Ok(Sent {
+        to: reached.ip().to_string(),
+        port: reached.port(),
+        kind: Kind::File,
+        name: Some(name),
+        size,
+        hash: Some(hash),
+        mime: None,
+        resumed_from: Some(offset),
+        encrypted: channel.is_encrypted(),
+        compression: channel.compression(),
+    })
+}
+
+/// Streams the content of the file `f`, found at `file`, from the `offset` the receiver asked to resume from,
+/// followed by the hash of the whole file, which is returned.
+///
+/// The file is hashed as it's read, so the hash is the one of the content sent, even if the file changed since it was announced.
+fn stream_file(
+    channel: &mut Channel<TcpStream>,
+    mut f: std::fs::File,
+    file: &str,
+    size: u64,
+    offset: u64,
+) -> Result<String> {
+    if offset > size {
+        bail!(
+            "The receiver asked to resume from byte {} of a {} bytes file",
+            offset,
+            size
+        );
+    } else if offset > 0 {
+        eprintln!("Resuming the transfer of {} from byte {}", file, offset);
+    }
+
+    // The receiver already holds the beginning of the file, it's only hashed
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(&mut (&mut f).take(offset), &mut hasher)
+        .context(format!("Reading file {} up to byte {}", file, offset))?;
+
+    // Never more than announced, even if the file grew meanwhile
+    let mut content = Hashed::resume(BufReader::new(f).take(size - offset), hasher);
+    let sent = channel
+        .write_stream(&mut content)
+        .context(format!("Streaming file {} to the TCP stream", file))?;
+    if offset + sent != size {
+        bail!("{} changed while it was being sent", file);
+    }
+
+    let hash = content.hash();
+    channel
+        .write_message(&SysRaven::Trailer { hash: hash.clone() })
+        .context("Writing the hash of the file")?;
+
+    Ok(hash)
+}
+
+/// Waits for the receiver to confirm a raven was stored, returning why it refused it otherwise.
+fn confirmation(channel: &mut Channel<TcpStream>) -> Result<Option<Rejection>> {
+    match channel
+        .read_message()
+        .context("Waiting for the receiver to store the content")?
+    {
+        SysRaven::Stored => Ok(None),
+        SysRaven::Reject(reason) => Ok(Some(reason)),
+        reply => bail!(
+            "Expected the receiver to store the content but it answered {:?}",
+            reply
+        ),
+    }
+}
+
+/// The compressions offered to the receiver, if the sender is configured to compress.
+fn offered(config: &Config) -> Vec<Compression> {
+    if config.sender.compress {
+        compression::SUPPORTED.to_vec()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Identifies the transfer of a file, it only depends on where the file is and what it holds.
+/// So sending the same file again after an interrupted transfer gives the receiver the same id to resume it.
+pub(crate) fn transfer_id(file: &str, hash: &str) -> String {
+    let path = std::fs::canonicalize(file)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or(file.to_string());
+
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(path.as_bytes());
+    hasher.update(hash.as_bytes());
+
+    hasher.finalize().to_hex()[..32].to_string()
+}
+
+/// Connects to the receiver at the destination `to`, trying its addresses in order until one answers.
+/// Returns the address reached.
+fn connect(to: &Destination) -> Result<(TcpStream, SocketAddr)> {
+    let addresses = to
+        .addresses
+        .iter()
+        .map(|ip| SocketAddr::new(*ip, to.port))
+        .collect::<Vec<_>>();
+    let stream = TcpStream::connect(&addresses[..])
+        .context(format!("Connecting to the target client at {}", to))?;
+    let reached = stream.peer_addr()?;
+    eprintln!("Connected to {}", reached);
+
+    Ok((stream, reached))
+}
+
+/// Announces the raven to the receiver using the framed protocol.
+/// First exchanges headers with the receiver to make sure both ends speak the same protocol version,
+/// then sends the `Helo`, both ends prove their identities and the sender waits for the receiver to accept the raven.
+///
+/// If the sender is configured to encrypt, both ends agree on the keys during the handshake
+/// and the returned channel encrypts everything that follows.
+fn fly(
+    mut stream: TcpStream,
+    reached: SocketAddr,
+    mut helo: Helo,
+    to: &Destination,
+    config: &Config,
+    identity: &Identity,
+) -> Result<Channel<TcpStream>> {
+    Header::new(helo.kind)
+        .write_to(&mut stream)
+        .context("Writing the raven header")?;
+
+    let reply = Header::read_from(&mut stream).context("Reading the receiver header")?;
+    reply.check_version()?;
+
+    let mut channel = Channel::new(stream);
+    let ephemeral = config.sender.encrypt.then(Ephemeral::new);
+    helo.ephemeral = ephemeral.as_ref().map(|ephemeral| ephemeral.public_key());
+
+    channel
+        .write_message(&SysRaven::Helo(helo.clone()))
+        .context("Announcing the raven")?;
+
+    let (nonce, key, theirs, signature) = match channel
+        .read_message()
+        .context("Waiting for the receiver challenge")?
+    {
+        SysRaven::Challenge {
+            nonce,
+            key,
+            ephemeral,
+            signature,
+        } => (nonce, key, ephemeral, signature),
+        SysRaven::Reject(reason) => bail!("The receiver refused the raven: {}", reason),
+        reply => bail!(
+            "Expected the receiver challenge but it answered {:?}",
+            reply
+        ),
     };
 
-    let encoded = bincode::serialize(&rv).context("Serializing file")?;
+    // Makes sure the receiver is who it claims to be
+    let transcript = raven::transcript(&helo, &nonce, &key, theirs.as_deref());
+    trust::verify(
+        &key,
+        &raven::signed(Role::Receiver, &transcript),
+        &signature,
+    )
+    .context("The receiver failed to prove its identity")?;
+
+    let trust = TrustStore::open(config).context("Opening the trust store")?;
+    if let Some(peer) = trust.at(&reached.ip().to_string()) {
+        if peer.key != key {
+            bail!(
+                "The receiver at {} isn't the trusted peer {}, it has the key [{}] instead of [{}]",
+                reached.ip(),
+                peer.name,
+                trust::fingerprint(&key),
+                trust::fingerprint(&peer.key)
+            );
+        }
+    }
+
+    // The peer of the address book the raven goes to may be expected to own a given key
+    if let Some((
+        name,
+        Peer {
+            fingerprint: Some(fingerprint),
+            ..
+        },
+    )) = &to.peer
+    {
+        if *fingerprint != trust::fingerprint(&key) {
+            bail!(
+                "The receiver at {} isn't the peer {} of the address book, it has the key [{}] instead of [{}]",
+                reached.ip(),
+                name,
+                trust::fingerprint(&key),
+                fingerprint
+            );
+        }
+    }
+
+    // Any device on the LAN may answer with a name, only the one owning the key it announced is trusted to be it
+    if let Some((name, fingerprint)) = &to.device {
+        if *fingerprint != trust::fingerprint(&key) {
+            bail!(
+                "The receiver at {} isn't the device {} that answered on the LAN, it has the key [{}] instead of [{}]",
+                reached.ip(),
+                name,
+                trust::fingerprint(&key),
+                fingerprint
+            );
+        }
+    }
+
+    channel
+        .write_message(&SysRaven::Proof {
+            signature: identity.sign(&raven::signed(Role::Sender, &transcript)),
+        })
+        .context("Proving the sender identity")?;
 
-    stream
-        .write_all(&encoded)
-        .context("Writing to TCP stream")?;
-    println!("File sent: {:?}", rv);
+    match (ephemeral, theirs) {
+        (Some(ephemeral), Some(theirs)) => {
+            channel.encrypt(ephemeral.agree(&theirs, &transcript, Role::Sender)?)
+        }
+        (Some(_), None) => bail!("The receiver didn't agree to encrypt the raven"),
+        _ => (),
+    }
+
+    match channel
+        .read_message()
+        .context("Waiting for the receiver to accept the raven")?
+    {
+        SysRaven::Accept { compression } => {
+            if let Some(compression) = compression {
+                if !helo.compression.contains(&compression) {
+                    bail!(
+                        "The receiver picked {} compression, which wasn't offered",
+                        compression
+                    );
+                }
+                eprintln!("Compressing the raven with {}", compression);
+                channel.compress(compression);
+            }
 
-    Ok(())
+            Ok(channel)
+        }
+        SysRaven::Reject(reason) => bail!("The receiver refused the raven: {}", reason),
+        reply => bail!(
+            "Expected the receiver to accept the raven but it answered {:?}",
+            reply
+        ),
+    }
 }
@@ -0,0 +1,436 @@
+use std::{io::ErrorKind, net::IpAddr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+use serde::{Deserialize, Serialize};
+use toml::value::Datetime;
+
+use crate::{
+    cli::TrustSubcommands,
+    config::{Config, Limits},
+    output::{IdentityRecord, Output, PeerRecord, PeersRecord},
+    util,
+};
+
+/// The identity of a raven client: an ed25519 keypair generated on the first run.
+///
+/// The secret key is stored in `identity.key` and the public key in `identity.pub`, both in the raven home folder.
+pub struct Identity {
+    key: SigningKey,
+}
+
+/// The registry of the peers this client trusts, stored in `trust.toml` in the raven home folder.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrustStore {
+    #[serde(default)]
+    peers: Vec<TrustedPeer>,
+}
+
+/// A peer trusted by its public key and, optionally, by the address it connects from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedPeer {
+    pub name: String,
+    /// The hex encoded ed25519 public key of the peer.
+    pub key: String,
+    /// The ip address the peer must connect from to be trusted.
+    pub address: Option<String>,
+    pub since: Datetime,
+    /// The limits of the receiver this peer overrides.
+    #[serde(default)]
+    pub limits: Limits,
+    /// Whether the clipboards this peer sends are put on the clipboard right away.
+    #[serde(default)]
+    pub clipboard: bool,
+}
+
+impl Identity {
+    /// Loads the identity from the raven home folder, generating a new one if there is none yet.
+    pub fn load(config: &Config) -> Result<Self> {
+        let key_path = format!("{}/identity.key", config.raven_home);
+
+        match std::fs::read_to_string(&key_path) {
+            Ok(secret) => {
+                let secret = hex::decode(secret.trim())
+                    .ok()
+                    .and_then(|secret| <[u8; 32]>::try_from(secret).ok())
+                    .context(format!("The identity key at {} is corrupted", &key_path))?;
+
+                Ok(Self {
+                    key: SigningKey::from_bytes(&secret),
+                })
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let identity = Self {
+                    key: SigningKey::generate(&mut OsRng),
+                };
+                identity
+                    .save(config)
+                    .context("Failed to save the just created identity")?;
+
+                Ok(identity)
+            }
+            Err(e) => Err(e).context(format!(
+                "Failed to read the identity key from {}",
+                &key_path
+            )),
+        }
+    }
+
+    fn save(&self, config: &Config) -> Result<()> {
+        let key_path = format!("{}/identity.key", config.raven_home);
+        let pub_path = format!("{}/identity.pub", config.raven_home);
+
+        util::ensure_folder(&config.raven_home)?;
+        util::write_private(&key_path, hex::encode(self.key.to_bytes()).as_bytes())
+            .context(format!("Saving the identity key to {}", &key_path))?;
+        std::fs::write(&pub_path, self.public_key())
+            .context(format!("Saving the public key to {}", &pub_path))?;
+
+        Ok(())
+    }
+
+    /// The hex encoded public key of this identity.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.key.verifying_key().to_bytes())
+    }
+
+    /// The fingerprint of the public key of this identity.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+
+    /// Signs `message` with the secret key of this identity.
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.key.sign(message).to_bytes().to_vec()
+    }
+}
+
+/// Checks that `signature` was made over `message` by the owner of the hex encoded public `key`.
+pub fn verify(key: &str, message: &[u8], signature: &[u8]) -> Result<()> {
+    let key = parse_key(key)?;
+    let signature = Signature::from_slice(signature).context("Malformed signature")?;
+
+    key.verify(message, &signature)
+        .context("The signature doesn't match the key")
+}
+
+/// A short and human friendly digest of a hex encoded public key.
+pub fn fingerprint(key: &str) -> String {
+    let digest = blake3::hash(key.as_bytes()).to_hex();
+
+    digest[..32]
+        .as_bytes()
+        .chunks(4)
+        .map(|group| std::str::from_utf8(group).unwrap())
+        .collect::<Vec<&str>>()
+        .join(":")
+}
+
+/// Whether `fingerprint` is shaped like the ones `fingerprint` makes.
+pub fn is_fingerprint(fingerprint: &str) -> bool {
+    let groups: Vec<&str> = fingerprint.split(':').collect();
+
+    groups.len() == 8
+        && groups.iter().all(|group| {
+            group.len() == 4
+                && group
+                    .chars()
+                    .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        })
+}
+
+fn parse_key(key: &str) -> Result<VerifyingKey> {
+    let bytes = hex::decode(key)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .context(format!("`{}` isn't a hex encoded ed25519 public key", key))?;
+
+    VerifyingKey::from_bytes(&bytes).context(format!("`{}` isn't a valid ed25519 public key", key))
+}
+
+impl TrustStore {
+    pub fn open(config: &Config) -> Result<Self> {
+        let path = format!("{}/trust.toml", config.raven_home);
+        if !std::path::Path::new(&path).exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        toml::from_str(&content).context(format!(
+            "Failed to deserialize the trust store from {}",
+            &path
+        ))
+    }
+
+    /// Saves the trust store, replacing the one in the raven home folder.
+    pub fn save(&self, config: &Config) -> Result<()> {
+        util::ensure_folder(&config.raven_home)?;
+
+        util::locked(&format!("{}/trust.lock", config.raven_home), || {
+            self.write(config)
+        })
+    }
+
+    /// Opens the trust store, applies `f` to it and saves it, without another process changing it meanwhile.
+    pub fn update<T>(config: &Config, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        util::ensure_folder(&config.raven_home)?;
+
+        util::locked(&format!("{}/trust.lock", config.raven_home), || {
+            let mut store = Self::open(config)?;
+            let result = f(&mut store)?;
+            store.write(config)?;
+
+            Ok(result)
+        })
+    }
+
+    fn write(&self, config: &Config) -> Result<()> {
+        let content = toml::to_string(self).context("Serializing the trust store before saving")?;
+        util::write_atomic(
+            &format!("{}/trust.toml", config.raven_home),
+            content.as_bytes(),
+        )
+        .context(format!(
+            "Saving the trust store to {}/trust.toml",
+            config.raven_home
+        ))
+    }
+
+    /// Trusts a new peer by its public key, replacing any peer with the same name or key.
+    /// The peer keeps the limits and the clipboard sharing it was trusted with, `limits` overrides them
+    /// and with `clipboard` the clipboards it sends are put on the clipboard right away.
+    pub fn add(
+        &mut self,
+        name: String,
+        key: String,
+        address: Option<String>,
+        limits: Limits,
+        clipboard: bool,
+    ) -> Result<()> {
+        parse_key(&key)?;
+        // Written the way the addresses ravens come from are, to compare them
+        let address = address
+            .map(|address| match address.parse::<IpAddr>() {
+                Ok(ip) => Ok(ip.to_string()),
+                Err(_) => Err(anyhow!("Invalid ip address {}", address)),
+            })
+            .transpose()?;
+
+        let (limits, clipboard) = match self
+            .peers
+            .iter()
+            .find(|peer| peer.key == key)
+            .or_else(|| self.peers.iter().find(|peer| peer.name == name))
+        {
+            Some(peer) => (
+                peer.limits.overridden_by(&limits),
+                clipboard || peer.clipboard,
+            ),
+            None => (limits, clipboard),
+        };
+
+        self.peers
+            .retain(|peer| peer.name != name && peer.key != key);
+        self.peers.push(TrustedPeer {
+            name,
+            key,
+            address,
+            since: util::chrono_to_toml_datetime(chrono::Utc::now()),
+            limits,
+            clipboard,
+        });
+
+        Ok(())
+    }
+
+    /// Stops trusting the peer with the given name, public key or fingerprint.
+    /// Returns whether a peer was removed.
+    pub fn remove(&mut self, peer: &str) -> bool {
+        let before = self.peers.len();
+        self.peers.retain(|trusted| !trusted.matches(peer));
+
+        before != self.peers.len()
+    }
+
+    /// Finds the trusted peer that owns `key`, if it connected from an address it's trusted at.
+    pub fn find(&self, key: &str, address: &str) -> Option<&TrustedPeer> {
+        self.peers
+            .iter()
+            .find(|peer| peer.key == key && peer.address.iter().all(|trusted| trusted == address))
+    }
+
+    /// Finds the trusted peer expected at `address`.
+    pub fn at(&self, address: &str) -> Option<&TrustedPeer> {
+        self.peers
+            .iter()
+            .find(|peer| peer.address.as_deref() == Some(address))
+    }
+
+    /// The trusted peers, as listed by `list`.
+    pub fn listing(&self) -> PeersRecord {
+        PeersRecord {
+            peers: self
+                .peers
+                .iter()
+                .map(|peer| PeerRecord {
+                    name: peer.name.clone(),
+                    fingerprint: fingerprint(&peer.key),
+                    key: peer.key.clone(),
+                    address: peer.address.clone(),
+                    since: util::fmt_rfc3339(peer.since),
+                    clipboard: peer.clipboard,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn list(&self) {
+        println!("Trusted peers:");
+        for peer in self.peers.iter() {
+            println!(
+                "{} [{}]{} since {}{}",
+                peer.name,
+                fingerprint(&peer.key),
+                peer.address
+                    .as_ref()
+                    .map(|address| format!(" at {}", address))
+                    .unwrap_or_default(),
+                util::fmt_datetime(util::toml_to_chrono_datetime(peer.since)),
+                if peer.clipboard {
+                    ", shares the clipboard"
+                } else {
+                    ""
+                }
+            );
+        }
+    }
+}
+
+impl TrustedPeer {
+    fn matches(&self, peer: &str) -> bool {
+        self.name == peer || self.key == peer || fingerprint(&self.key) == peer
+    }
+}
+
+pub fn manage(command: TrustSubcommands, config: Config, output: Output) -> Result<()> {
+    match command {
+        TrustSubcommands::Add {
+            name,
+            key,
+            address,
+            max_message,
+            max_file,
+            max_daily,
+            clipboard,
+        } => {
+            let limits = Limits {
+                max_message,
+                max_file,
+                max_daily,
+                max_storage: None,
+            };
+            TrustStore::update(&config, |store| {
+                store.add(name, key, address, limits, clipboard)
+            })?;
+        }
+        TrustSubcommands::List => {
+            let store = TrustStore::open(&config)?;
+            output.print(&store.listing(), || store.list())?
+        }
+        TrustSubcommands::Remove { peer } => TrustStore::update(&config, |store| {
+            if !store.remove(&peer) {
+                bail!("Peer `{}` not found", peer);
+            }
+
+            Ok(())
+        })?,
+        TrustSubcommands::Fingerprint => {
+            let identity = Identity::load(&config)?;
+            let record = IdentityRecord {
+                fingerprint: identity.fingerprint(),
+                key: identity.public_key(),
+            };
+            output.print(&record, || {
+                println!("Fingerprint: {}", record.fingerprint);
+                println!("Public key: {}", record.key);
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let identity = Identity {
+            key: SigningKey::generate(&mut OsRng),
+        };
+        let other = Identity {
+            key: SigningKey::generate(&mut OsRng),
+        };
+
+        let signature = identity.sign(b"helo");
+        assert!(verify(&identity.public_key(), b"helo", &signature).is_ok());
+        assert!(verify(&identity.public_key(), b"hello", &signature).is_err());
+        assert!(verify(&other.public_key(), b"helo", &signature).is_err());
+    }
+
+    #[test]
+    fn test_trust_store() {
+        let identity = Identity {
+            key: SigningKey::generate(&mut OsRng),
+        };
+        let key = identity.public_key();
+
+        let mut store = TrustStore::default();
+        assert!(store
+            .add(
+                "laptop".into(),
+                "not a key".into(),
+                None,
+                Limits::default(),
+                false
+            )
+            .is_err());
+
+        let limits = Limits {
+            max_file: Some(1024),
+            ..Limits::default()
+        };
+        store
+            .add("laptop".into(), key.clone(), None, limits, true)
+            .unwrap();
+        assert_eq!(store.peers[0].limits.max_file, Some(1024));
+
+        // Adding the peer again keeps what it was trusted with
+        let limits = Limits {
+            max_daily: Some(4096),
+            ..Limits::default()
+        };
+        store
+            .add(
+                "laptop".into(),
+                key.clone(),
+                Some("10.0.0.5".into()),
+                limits,
+                false,
+            )
+            .unwrap();
+        assert_eq!(store.peers.len(), 1);
+        assert_eq!(store.peers[0].limits.max_file, Some(1024));
+        assert_eq!(store.peers[0].limits.max_daily, Some(4096));
+        assert!(store.peers[0].clipboard);
+        assert!(store.find(&key, "10.0.0.5").is_some());
+        assert!(store.find(&key, "10.0.0.6").is_none());
+
+        assert!(is_fingerprint(&identity.fingerprint()));
+        assert!(!is_fingerprint(&key));
+        assert!(store.remove(&identity.fingerprint()));
+        assert!(store.find(&key, "10.0.0.5").is_none());
+    }
+}
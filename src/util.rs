@@ -1,11 +1,23 @@
 use std::{ffi::OsStr, path::Path};
 
-use anyhow::{Context, Result};
-use chrono::{Datelike, Local, TimeZone, Timelike, Utc};
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, Local, SecondsFormat, TimeZone, Timelike, Utc};
 use toml::value::{Date, Datetime, Time};
 
-pub const LISTEN_DEFAULT_ADDRESS: &str = "0.0.0.0";
+/// Every ipv6 and ipv4 address of the device, `rvd` listens on both.
+pub const LISTEN_DEFAULT_ADDRESS: &str = "::";
 pub const LISTEN_DEFAULT_PORT: u16 = 12345;
+pub const DISCOVERY_DEFAULT_PORT: u16 = 12346;
+
+/// The folder inside the data folder where raven keeps the transfers in progress, no received file may take its name.
+pub const PARTIALS_FOLDER: &str = ".partial";
+/// The names of devices on windows, no file may be called after them whatever its extension.
+const DEVICE_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+/// The longest filename most file systems allow, in bytes.
+const MAX_FILENAME_LEN: usize = 255;
 
 pub fn listen_default_address() -> String {
     LISTEN_DEFAULT_ADDRESS.into()
@@ -15,12 +27,159 @@ pub fn listen_default_port() -> u16 {
     LISTEN_DEFAULT_PORT
 }
 
-pub fn is_ipv4_address(address: &str) -> bool {
-    address.parse::<std::net::Ipv4Addr>().is_ok()
+pub fn discovery_default_port() -> u16 {
+    DISCOVERY_DEFAULT_PORT
+}
+
+pub fn default_true() -> bool {
+    true
+}
+
+/// The hostname of this machine, used as the default name of the device.
+pub fn device_name() -> String {
+    gethostname::gethostname()
+        .into_string()
+        .unwrap_or("raven".into())
+}
+
+/// Whether `address` is an ipv4 or ipv6 address.
+pub fn is_ip_address(address: &str) -> bool {
+    address.parse::<std::net::IpAddr>().is_ok()
+}
+
+/// Whether `name` is a valid hostname: dot separated labels of letters, digits and dashes.
+pub fn is_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 253
+        && name.trim_end_matches('.').split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Formats an address and a port together, an ipv6 address between brackets.
+pub fn fmt_address(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+/// Whether an executable called `command` is in one of the folders of `$PATH`.
+pub fn on_path(command: &str) -> bool {
+    std::env::var_os("PATH")
+        .map(|path| std::env::split_paths(&path).any(|folder| folder.join(command).is_file()))
+        .unwrap_or(false)
+}
+
+pub fn basename(path: &str) -> &str {
+    path.rfind('/').map(|pos| &path[pos + 1..]).unwrap_or(path)
+}
+
+/// Turns the name of a received file, chosen by the sender, into a filename that can only live in the data folder.
+///
+/// Anything up to the last `/` or `\` is dropped, so are the trailing dots and spaces windows ignores.
+/// Names that still aren't safe are refused: empty, `.` or `..`, holding control characters, too long,
+/// or reserved (device names on windows and the folder of the partial transfers).
+pub fn safe_filename(name: &str) -> Result<String> {
+    let stripped = match name.rsplit(['/', '\\']).next().unwrap_or_default() {
+        "." | ".." => "",
+        last => last.trim_end_matches(['.', ' ']),
+    };
+    // `nul.txt` is as much the device as `nul`
+    let device = stripped.split('.').next().unwrap_or_default().trim_end();
+
+    if stripped.is_empty() {
+        bail!("The name {:?} has no filename", name);
+    } else if stripped.chars().any(char::is_control) {
+        bail!("The name {:?} holds control characters", name);
+    } else if stripped.len() > MAX_FILENAME_LEN {
+        bail!(
+            "The name {:?} is longer than {} bytes",
+            name,
+            MAX_FILENAME_LEN
+        );
+    } else if stripped == PARTIALS_FOLDER
+        || DEVICE_NAMES
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(device))
+    {
+        bail!("The name {:?} is reserved", name);
+    }
+
+    Ok(stripped.to_string())
+}
+
+/// Makes sure `path` names an entry right inside `folder`, following any symbolic link on the way,
+/// so nothing is ever written outside of it.
+pub fn ensure_inside(folder: &str, path: &str) -> Result<()> {
+    let folder =
+        std::fs::canonicalize(folder).context(format!("Resolving the folder {}", folder))?;
+    let entry = Path::new(path);
+    let parent = entry
+        .parent()
+        .and_then(|parent| std::fs::canonicalize(parent).ok());
+
+    let inside = parent.is_some_and(|parent| parent == folder)
+        && entry
+            .file_name()
+            .is_some_and(|name| name != PARTIALS_FOLDER);
+    if !inside {
+        bail!("{} isn't inside {}", path, folder.display());
+    }
+
+    Ok(())
+}
+
+/// Computes the hex encoded blake3 hash of the content of the file at `path`.
+pub fn hash_file(path: &str) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    hasher
+        .update_reader(std::fs::File::open(path).context(format!("Opening {} to hash it", path))?)
+        .context(format!("Hashing {}", path))?;
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Writes `content` to the file at `path`, making it only readable by its owner.
+pub fn write_private(path: &str, content: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut f = options.open(path).context(format!("Opening {}", path))?;
+    std::io::Write::write_all(&mut f, content).context(format!("Writing {}", path))?;
+
+    Ok(())
+}
+
+/// Replaces the file at `path` with `content` at once, so readers see either the old or the new content.
+pub fn write_atomic(path: &str, content: &[u8]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, content).context(format!("Writing {}", &tmp))?;
+    std::fs::rename(&tmp, path).context(format!("Replacing {}", path))?;
+
+    Ok(())
 }
 
-pub fn basename<'path>(path: &'path str) -> &'path str {
-    path.rfind("/").map(|pos| &path[pos + 1..]).unwrap_or(path)
+/// Runs `f` holding an exclusive lock on the file at `lock`, so it never runs concurrently with another
+/// function locking the same file, be it in another thread or in another process.
+pub fn locked<T>(lock: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock)
+        .context(format!("Opening the lock {}", lock))?;
+    fs2::FileExt::lock_exclusive(&file).context(format!("Locking {}", lock))?;
+
+    // The lock is released when the file is closed
+    f()
 }
 
 /// Ensures that the given folder does exist.
@@ -28,31 +187,56 @@ pub fn ensure_folder(path: &str) -> Result<()> {
     let path = std::path::Path::new(path);
 
     if !path.exists() {
-        std::fs::create_dir_all(&path)
+        std::fs::create_dir_all(path)
             .context(format!("Creating folder: {}", &path.to_str().unwrap()))
     } else {
         Ok(())
     }
 }
 
-/// Returns a filename that doesn't collide with the existing files.
+/// The total size of the files in the given folder and its subfolders.
+pub fn folder_size(path: &str) -> u64 {
+    fn size(path: &Path) -> u64 {
+        std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| match entry.metadata() {
+                        Ok(meta) if meta.is_dir() => size(&entry.path()),
+                        Ok(meta) => meta.len(),
+                        Err(_) => 0,
+                    })
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    size(Path::new(path))
+}
+
+/// Returns a filename that doesn't collide with the existing files, numbering it before its extension (`notes_1.txt`).
+/// Dangling symbolic links count as existing files.
 pub fn non_colliding_filename(path: &str) -> String {
+    let exists = |path: &Path| path.symlink_metadata().is_ok();
     let mut i = 1;
     let path = Path::new(path);
 
-    if !path.exists() {
+    if !exists(path) {
         return path.to_str().unwrap().to_string();
     }
 
     let filename = path.file_stem().unwrap_or(OsStr::new("")).to_str().unwrap();
-    let extension = path.extension().unwrap_or(OsStr::new("")).to_str().unwrap();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_str().unwrap()))
+        .unwrap_or_default();
     let source = path.parent().unwrap_or(Path::new(".")).to_str().unwrap();
 
-    let mut path = format!("{}/{}{}", source, filename, extension);
+    let mut path = format!("{}/{}_{}{}", source, filename, i, extension);
 
-    while Path::new(&path).exists() {
-        path = format!("{}/{}_{}.{}", source, filename, i, extension);
+    while exists(Path::new(&path)) {
         i += 1;
+        path = format!("{}/{}_{}{}", source, filename, i, extension);
     }
 
     path
@@ -97,6 +281,34 @@ pub fn toml_to_chrono_datetime(date: Datetime) -> chrono::NaiveDateTime {
     }
 }
 
+/// Parses a local date (`YYYY-MM-DD`) or date and time (`YYYY-MM-DD HH:MM[:SS]`) into utc.
+/// A date alone means the start of the day, or its end if `end_of_day`.
+pub fn parse_datetime(date: &str, end_of_day: bool) -> Result<chrono::NaiveDateTime> {
+    let date = date.trim().replace('/', "-");
+
+    let local = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S"]
+        .iter()
+        .find_map(|format| chrono::NaiveDateTime::parse_from_str(&date, format).ok())
+        .or_else(|| {
+            let day = chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok()?;
+            if end_of_day {
+                day.and_hms_opt(23, 59, 59)
+            } else {
+                day.and_hms_opt(0, 0, 0)
+            }
+        })
+        .context(format!(
+            "Invalid date `{}`, use YYYY-MM-DD or YYYY-MM-DD HH:MM",
+            date
+        ))?;
+
+    Ok(Local
+        .from_local_datetime(&local)
+        .earliest()
+        .map(|date| date.naive_utc())
+        .unwrap_or(local))
+}
+
 pub fn fmt_datetime(date: chrono::NaiveDateTime) -> String {
     let date = Utc.from_utc_datetime(&date);
     let date = date.with_timezone(&Local);
@@ -104,12 +316,100 @@ pub fn fmt_datetime(date: chrono::NaiveDateTime) -> String {
     date.format("%Y/%m/%d %H:%M:%S").to_string()
 }
 
+/// Formats a utc date as RFC 3339, for the structured output.
+pub fn fmt_rfc3339(date: Datetime) -> String {
+    toml_to_chrono_datetime(date)
+        .and_utc()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_safe_filename() {
+        let safe = |name: &str| super::safe_filename(name).ok();
+
+        assert_eq!(safe("notes.txt").as_deref(), Some("notes.txt"));
+        assert_eq!(safe(".bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(safe("../../.bashrc").as_deref(), Some(".bashrc"));
+        assert_eq!(safe("/etc/passwd").as_deref(), Some("passwd"));
+        assert_eq!(safe("..\\..\\Windows\\win.ini").as_deref(), Some("win.ini"));
+        assert_eq!(safe("C:\\evil.exe").as_deref(), Some("evil.exe"));
+        assert_eq!(safe("report.pdf. . ").as_deref(), Some("report.pdf"));
+
+        let hostile = [
+            "",
+            ".",
+            "..",
+            "...",
+            "../",
+            "/",
+            "photos/",
+            "photos/..",
+            "a\\..",
+            "nul\0.txt",
+            "line\nbreak",
+            "bell\u{7}",
+            "esc\u{1b}[31m",
+            "del\u{7f}",
+            ".partial",
+            "../.partial",
+            "CON",
+            "con.txt",
+            "Nul",
+            "lpt1.log",
+            "COM9 .tar.gz",
+        ];
+        for name in hostile {
+            assert_eq!(safe(name), None, "{:?} should be refused", name);
+        }
+        assert_eq!(safe(&"a".repeat(256)), None);
+        assert!(safe(&"a".repeat(255)).is_some());
+        assert!(safe("console.log").is_some());
+    }
+
+    #[test]
+    fn test_addresses() {
+        assert!(super::is_ip_address("10.0.0.5"));
+        assert!(super::is_ip_address("fe80::1"));
+        assert!(!super::is_ip_address("[::1]"));
+        assert!(!super::is_ip_address("10.0.0.5:4000"));
+
+        assert!(super::is_hostname("laptop"));
+        assert!(super::is_hostname("nas-2.home.arpa."));
+        assert!(!super::is_hostname("-laptop"));
+        assert!(!super::is_hostname("lap top"));
+        assert!(!super::is_hostname("laptop:4000"));
+        assert!(!super::is_hostname("a..b"));
+
+        assert_eq!(super::fmt_address("10.0.0.5", 12345), "10.0.0.5:12345");
+        assert_eq!(super::fmt_address("::1", 12345), "[::1]:12345");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_ensure_inside() {
+        let dir = std::env::temp_dir().join(format!("raven-test-inside-{}", std::process::id()));
+        let data = dir.join("data");
+        std::fs::create_dir_all(data.join("photos")).unwrap();
+        std::os::unix::fs::symlink(&dir, data.join("escape")).unwrap();
+        let data = data.to_str().unwrap();
+
+        assert!(super::ensure_inside(data, &format!("{}/notes.txt", data)).is_ok());
+        assert!(super::ensure_inside(data, &format!("{}/photos", data)).is_ok());
+        assert!(super::ensure_inside(data, &format!("{}/photos/beach.png", data)).is_err());
+        assert!(super::ensure_inside(data, &format!("{}/../notes.txt", data)).is_err());
+        assert!(super::ensure_inside(data, &format!("{}/escape/notes.txt", data)).is_err());
+        assert!(super::ensure_inside(data, &format!("{}/.partial", data)).is_err());
+        assert!(super::ensure_inside(data, "/etc/passwd").is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
     #[test]
     fn test_name_collision() {
         // Some example files
-        let files = vec![
+        let files = [
             "/tmp/file.txt",
             "/tmp/file_1.txt",
             "/tmp/file.txt.gz",
@@ -140,4 +440,28 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_numbered_filename() {
+        let dir = std::env::temp_dir().join(format!("raven-test-numbered-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap();
+        for file in ["notes.txt", "notes_1.txt", "README", "archive.tar.gz"] {
+            std::fs::write(format!("{}/{}", dir, file), "").unwrap();
+        }
+        std::os::unix::fs::symlink("/nowhere", format!("{}/dangling", dir)).unwrap();
+
+        let numbered = |file: &str| super::non_colliding_filename(&format!("{}/{}", dir, file));
+        assert_eq!(numbered("notes.txt"), format!("{}/notes_2.txt", dir));
+        assert_eq!(numbered("README"), format!("{}/README_1", dir));
+        assert_eq!(
+            numbered("archive.tar.gz"),
+            format!("{}/archive.tar_1.gz", dir)
+        );
+        assert_eq!(numbered("dangling"), format!("{}/dangling_1", dir));
+        assert_eq!(numbered("new.txt"), format!("{}/new.txt", dir));
+
+        let _ = std::fs::remove_dir_all(dir);
+    }
 }